@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Error as IOError;
+use std::io::ErrorKind as IOErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::data::{self, ObjectId};
+use crate::index::{self, IndexEntry, Stat};
+use crate::object::{FileMode, ObjType};
+
+// stat cache of write_tree, same layout as the index but with entries for directories too.
+// a file whose stat did not change since the last write_tree keeps its id without being read
+// and a directory whose entries all kept their ids keeps its tree id
+pub const CACHE_PATH: &str = "./.yeet/cache";
+const CACHE_MAGIC: &[u8; 4] = b"YCCH";
+
+pub struct StatCache {
+    // entries of the last run, looked up by path
+    old: BTreeMap<Vec<u8>, IndexEntry>,
+    // entries seen in this run, paths that are gone are not carried over
+    new: Mutex<BTreeMap<Vec<u8>, IndexEntry>>,
+    now: i64,
+}
+
+impl StatCache {
+    // a missing or unreadable cache starts out empty, it only costs rehashing
+    pub fn load() -> Result<StatCache, IOError> {
+        let old = match fs::read(CACHE_PATH) {
+            Ok(x) => index::decode_entries(&x, CACHE_MAGIC).unwrap_or_default(),
+            Err(e) if e.kind() == IOErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        return Ok(StatCache::with_entries(old));
+    }
+
+    // a cache that finds nothing
+    pub fn empty() -> StatCache {
+        return StatCache::with_entries(BTreeMap::new());
+    }
+
+    fn with_entries(old: BTreeMap<Vec<u8>, IndexEntry>) -> StatCache {
+        return StatCache {
+            old,
+            new: Mutex::new(BTreeMap::new()),
+            now: index::now_secs(),
+        };
+    }
+
+    pub fn save(&self) -> Result<(), IOError> {
+        let new = self.new.lock().unwrap();
+        let bytes = index::encode_entries(CACHE_MAGIC, new.values());
+        return data::write_atomic(&PathBuf::from(CACHE_PATH), &bytes);
+    }
+
+    // id of the file at path if neither its stat nor its mode changed
+    pub fn file(&self, path: &[u8], mode: FileMode, stat: &Stat) -> Option<(ObjType, ObjectId)> {
+        let entry = self.old.get(path)?;
+        if entry.type_ == ObjType::Tree || entry.mode != mode || !entry.stat.matches(stat) {
+            return None;
+        }
+        self.keep(entry);
+        return Some((entry.type_, entry.id.clone()));
+    }
+
+    // id of the tree at path if its count entries were all found unchanged, count tells
+    // whether entries were added or removed since those are missing from the cache
+    pub fn tree(&self, path: &[u8], count: usize) -> Option<ObjectId> {
+        let entry = self.old.get(path)?;
+        if entry.type_ != ObjType::Tree || self.children(path) != count {
+            return None;
+        }
+        self.keep(entry);
+        return Some(entry.id.clone());
+    }
+
+    fn keep(&self, entry: &IndexEntry) {
+        let mut new = self.new.lock().unwrap();
+        new.insert(entry.path.clone(), entry.clone());
+    }
+
+    fn children(&self, path: &[u8]) -> usize {
+        let mut prefix = path.to_vec();
+        if !prefix.is_empty() {
+            prefix.push(b'/');
+        }
+        return self
+            .old
+            .range(prefix.clone()..)
+            .map(|x| x.0)
+            .take_while(|x| x.starts_with(&prefix))
+            .filter(|x| x.len() > prefix.len() && !x[prefix.len()..].contains(&b'/'))
+            .count();
+    }
+
+    pub fn insert(&self, path: Vec<u8>, mode: FileMode, type_: ObjType, id: ObjectId, stat: Stat) {
+        let stat = stat.unless_racy(self.now);
+        self.new.lock().unwrap().insert(
+            path.clone(),
+            IndexEntry {
+                path,
+                mode,
+                type_,
+                id,
+                stat,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_000_000;
+
+    fn stat(mtime_secs: i64, size: u64) -> Stat {
+        Stat {
+            mtime_secs,
+            mtime_nanos: 5,
+            size,
+            inode: 7,
+        }
+    }
+
+    fn id(x: &str) -> ObjectId {
+        ObjectId::hash(x.as_bytes())
+    }
+
+    // the cache as the next write_tree would load it
+    fn next_run(cache: StatCache) -> StatCache {
+        let mut next = StatCache::with_entries(cache.new.into_inner().unwrap());
+        next.now = NOW + 10;
+        return next;
+    }
+
+    fn run(entries: &[(&str, ObjType, Stat)]) -> StatCache {
+        let mut cache = StatCache::empty();
+        cache.now = NOW;
+        for (path, type_, stat) in entries {
+            let mode = match type_ {
+                ObjType::Tree => FileMode::Directory,
+                _ => FileMode::Regular,
+            };
+            cache.insert(path.as_bytes().to_vec(), mode, *type_, id(path), *stat);
+        }
+        return next_run(cache);
+    }
+
+    #[test]
+    fn unchanged_files_are_found() {
+        let cache = run(&[
+            ("a", ObjType::Blob, stat(NOW - 5, 3)),
+            ("big", ObjType::Chunks, stat(NOW - 5, 9)),
+            ("d", ObjType::Tree, Stat::default()),
+        ]);
+        let old = stat(NOW - 5, 3);
+        assert_eq!(
+            cache.file(b"a", FileMode::Regular, &old),
+            Some((ObjType::Blob, id("a")))
+        );
+        assert_eq!(
+            cache.file(b"big", FileMode::Regular, &stat(NOW - 5, 9)),
+            Some((ObjType::Chunks, id("big")))
+        );
+        assert_eq!(cache.file(b"a", FileMode::Executable, &old), None);
+        assert_eq!(cache.file(b"a", FileMode::Regular, &stat(NOW - 5, 4)), None);
+        assert_eq!(cache.file(b"a", FileMode::Regular, &stat(NOW - 4, 3)), None);
+        assert_eq!(cache.file(b"d", FileMode::Regular, &Stat::default()), None);
+        assert_eq!(cache.file(b"missing", FileMode::Regular, &old), None);
+
+        // only what was found is carried over to the next run
+        let next = next_run(cache);
+        assert!(next.old.contains_key(b"a".as_slice()));
+        assert!(next.old.contains_key(b"big".as_slice()));
+        assert!(!next.old.contains_key(b"d".as_slice()));
+    }
+
+    #[test]
+    fn racy_files_are_hashed_again() {
+        // written in the same second the last run started
+        let racy = stat(NOW, 3);
+        let cache = run(&[("a", ObjType::Blob, racy)]);
+        assert_eq!(cache.file(b"a", FileMode::Regular, &racy), None);
+        assert_eq!(cache.file(b"a", FileMode::Regular, &Stat::default()), None);
+    }
+
+    #[test]
+    fn trees_are_found_until_entries_change() {
+        let old = stat(NOW - 5, 1);
+        let cache = run(&[
+            ("d", ObjType::Tree, Stat::default()),
+            ("d/x", ObjType::Blob, old),
+            ("d/y", ObjType::Blob, old),
+            ("d/sub", ObjType::Tree, Stat::default()),
+            ("d/sub/z", ObjType::Blob, old),
+            ("", ObjType::Tree, Stat::default()),
+        ]);
+        assert_eq!(cache.children(b"d"), 3);
+        assert_eq!(cache.children(b""), 1);
+        assert_eq!(cache.tree(b"d", 3), Some(id("d")));
+        // an entry was added or removed
+        assert_eq!(cache.tree(b"d", 4), None);
+        assert_eq!(cache.tree(b"d", 2), None);
+        assert_eq!(cache.tree(b"d/x", 0), None);
+        assert_eq!(cache.tree(b"", 1), Some(id("")));
+    }
+}
@@ -0,0 +1,130 @@
+// content defined chunking for large files, boundaries are picked by a gear rolling hash
+// so an edit only moves the chunk boundaries close to it and the other chunks keep their ids
+
+pub const MIN_CHUNK: usize = 256 * 1024;
+pub const MAX_CHUNK: usize = 4 * 1024 * 1024;
+// files up to this size are stored as a single blob
+pub const CHUNK_THRESHOLD: usize = MAX_CHUNK;
+// 20 bits gives chunks of about 1 MiB on average
+const MASK: u64 = ((1 << 20) - 1) << 44;
+// a byte is shifted out of the hash after 64 steps
+const WINDOW: usize = 64;
+
+// fills the gear table with splitmix64 output so it is the same on every build
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    return table;
+}
+
+static GEAR: [u64; 256] = gear_table();
+
+// length of the first chunk in data
+// data has to hold MAX_CHUNK bytes unless it is the end of the file
+pub fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK);
+    let mut hash: u64 = 0;
+    for (i, b) in data.iter().enumerate().take(end).skip(MIN_CHUNK - WINDOW) {
+        hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+        if i >= MIN_CHUNK && hash & MASK == 0 {
+            return i + 1;
+        }
+    }
+    return end;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every chunk of data, the way data::write_file cuts it
+    fn split(mut data: &[u8]) -> Vec<&[u8]> {
+        let mut chunks = vec![];
+        while !data.is_empty() {
+            let (chunk, rest) = data.split_at(cut_point(data));
+            chunks.push(chunk);
+            data = rest;
+        }
+        return chunks;
+    }
+
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        return (0..len)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect();
+    }
+
+    // end offset of every chunk
+    fn cut_points(data: &[u8]) -> Vec<usize> {
+        let mut end = 0;
+        return split(data)
+            .iter()
+            .map(|x| {
+                end += x.len();
+                end
+            })
+            .collect();
+    }
+
+    #[test]
+    fn cut_points_are_deterministic() {
+        let data = random_bytes(4 * MAX_CHUNK, 1);
+        let cuts = cut_points(&data);
+        assert_eq!(cuts, cut_points(&data.clone()));
+        assert_eq!(*cuts.last().unwrap(), data.len());
+        assert!(cuts.len() > 2);
+        for (i, x) in split(&data).iter().enumerate() {
+            assert!(x.len() <= MAX_CHUNK);
+            assert!(x.len() >= MIN_CHUNK || i == cuts.len() - 1);
+        }
+        // boundaries depend on the content only, so they move along with it
+        let shifted = [random_bytes(1000, 2), data.clone()].concat();
+        let shifted_cuts = cut_points(&shifted);
+        assert_eq!(
+            shifted_cuts[1..],
+            cuts.iter().map(|x| x + 1000).collect::<Vec<_>>()[1..]
+        );
+
+        assert_eq!(cut_point(&data[..MIN_CHUNK]), MIN_CHUNK);
+        assert!(split(&[]).is_empty());
+    }
+
+    #[test]
+    fn edit_in_the_middle_keeps_other_chunks() {
+        let data = random_bytes(4 * MAX_CHUNK, 3);
+        let mut edited = data.clone();
+        edited.splice(
+            data.len() / 2..data.len() / 2 + 10,
+            b"edited in the middle".to_vec(),
+        );
+
+        let chunks = split(&data);
+        let edited_chunks = split(&edited);
+        let changed = edited_chunks.iter().filter(|x| !chunks.contains(x)).count();
+        assert!(chunks.len() > 4);
+        assert!(
+            changed <= 2,
+            "{} of {} chunks changed",
+            changed,
+            chunks.len()
+        );
+    }
+}
@@ -1,22 +1,62 @@
+use std::time::Duration;
+
+use crate::yeet::CheckoutMode;
+
 pub enum Options {
     Init,
     CatFile,
     HashFile,
-    WriteTree,
+    // threads to hash files on, None uses the jobs config
+    WriteTree {
+        jobs: Option<usize>,
+    },
     ReadTree,
     SetAuthor,
-    Commit,
-    Log,
-    Checkout,
+    Config,
+    // length ids are shortened to, None shows full ids
+    Commit {
+        abbrev: Option<usize>,
+    },
+    Log {
+        abbrev: Option<usize>,
+    },
+    Checkout {
+        mode: CheckoutMode,
+    },
     Tag,
-    K, //gitk
+    Pack,
+    Fsck,
+    // unreachable objects older than prune are removed, None keeps them all
+    Gc {
+        prune: Option<Duration>,
+        dry_run: bool,
+    },
+    // verbose also lists paths that are not ignored and the pattern deciding each path
+    CheckIgnore {
+        verbose: bool,
+    },
+    Add {
+        jobs: Option<usize>,
+    },
+    // cached only removes the paths from the index
+    Rm {
+        cached: bool,
+    },
+    // paths are written as they are in the tree of the source commit
+    Restore {
+        source: String,
+        paths: Vec<String>,
+    },
+    K {
+        abbrev: Option<usize>,
+    }, //gitk
 }
 
 pub struct Config {
     pub command: Options,
     pub args: Option<Vec<String>>,
 }
-pub fn parse_args(args: &Vec<String>) -> Option<Config> {
+pub fn parse_args(args: &[String]) -> Option<Config> {
     // No arguments given
     if args.len() == 1 {
         println!("No args provided");
@@ -68,13 +108,14 @@ pub fn parse_args(args: &Vec<String>) -> Option<Config> {
             });
         }
     } else if args[1] == "writetree" {
-        if args.len() > 2 {
+        let (jobs, rest) = split_jobs(args.get(2..).unwrap())?;
+        if !rest.is_empty() {
             println!("Too many arguments");
             print_help();
             return None;
         } else {
             return Some(Config {
-                command: Options::WriteTree,
+                command: Options::WriteTree { jobs },
                 args: None,
             });
         }
@@ -108,51 +149,86 @@ pub fn parse_args(args: &Vec<String>) -> Option<Config> {
                 args: Some(args.get(2..).unwrap().to_vec()),
             });
         }
+    } else if args[1] == "config" {
+        if args.len() > 4 {
+            println!("Too many arguments");
+            print_help();
+            return None;
+        } else if args.len() == 2 {
+            println!("No config key provided");
+            print_help();
+            return None;
+        } else {
+            return Some(Config {
+                command: Options::Config,
+                args: Some(args.get(2..).unwrap().to_vec()),
+            });
+        }
     } else if args[1] == "commit" {
-        if args.len() > 3 {
+        let (abbrev, rest) = split_abbrev(args.get(2..).unwrap())?;
+        if rest.len() > 1 {
             println!("Too many arguments");
             print_help();
             return None;
-        } else if args.len() != 3 {
+        } else if rest.len() != 1 {
             println!("No commit message provided");
             print_help();
             return None;
         } else {
             return Some(Config {
-                command: Options::Commit,
-                args: Some(args.get(2..).unwrap().to_vec()),
+                command: Options::Commit { abbrev },
+                args: Some(rest),
             });
         }
     } else if args[1] == "log" {
-        if args.len() > 3 {
+        let (abbrev, rest) = split_abbrev(args.get(2..).unwrap())?;
+        if rest.len() > 1 {
             println!("Too many arguments");
             print_help();
             return None;
-        } else if args.len() == 2 {
+        } else if rest.is_empty() {
             // if a commit id was not provided then pass HEAD as default
             return Some(Config {
-                command: Options::Log,
+                command: Options::Log { abbrev },
                 args: Some(vec!["HEAD".to_string()]),
             });
-        } else if args.len() == 3 {
+        } else {
             return Some(Config {
-                command: Options::Log,
-                args: Some(vec![args[2].clone()]),
+                command: Options::Log { abbrev },
+                args: Some(rest),
             });
         }
     } else if args[1] == "checkout" {
-        if args.len() > 3 {
+        let mut mode = CheckoutMode::Safe;
+        let mut rest = vec![];
+        for arg in args.get(2..).unwrap() {
+            let flag = match arg.as_str() {
+                "--force" | "-f" => CheckoutMode::Force,
+                "--carry" => CheckoutMode::Carry,
+                _ => {
+                    rest.push(arg.clone());
+                    continue;
+                }
+            };
+            if mode != CheckoutMode::Safe && mode != flag {
+                println!("Cannot use --force together with --carry");
+                print_help();
+                return None;
+            }
+            mode = flag;
+        }
+        if rest.len() > 1 {
             println!("Too many arguments");
             print_help();
             return None;
-        } else if args.len() != 3 {
+        } else if rest.is_empty() {
             println!("No commit id provided");
             print_help();
             return None;
         } else {
             return Some(Config {
-                command: Options::Checkout,
-                args: Some(args.get(2..).unwrap().to_vec()),
+                command: Options::Checkout { mode },
+                args: Some(rest),
             });
         }
     } else if args[1] == "tag" {
@@ -172,20 +248,155 @@ pub fn parse_args(args: &Vec<String>) -> Option<Config> {
                 args: Some(args.get(2..).unwrap().to_vec()),
             });
         }
-    } else if args[1] == "k" {
-        if args.len() > 3 {
+    } else if args[1] == "pack" || args[1] == "repack" {
+        if args.len() > 2 {
             println!("Too many arguments");
             print_help();
             return None;
-        } else if args.len() == 2 {
+        } else {
             return Some(Config {
-                command: Options::K,
+                command: Options::Pack,
                 args: None,
             });
-        } else if args.len() == 3 {
+        }
+    } else if args[1] == "fsck" {
+        if args.len() > 2 {
+            println!("Too many arguments");
+            print_help();
+            return None;
+        } else {
+            return Some(Config {
+                command: Options::Fsck,
+                args: None,
+            });
+        }
+    } else if args[1] == "gc" {
+        // defaults to keeping unreachable objects for 2 weeks
+        let mut prune = Some(Duration::from_secs(2 * 7 * 24 * 60 * 60));
+        let mut dry_run = false;
+        for arg in args.get(2..).unwrap() {
+            if let Some(age) = arg.strip_prefix("--prune=") {
+                match parse_age(age) {
+                    Some(x) => prune = x,
+                    None => {
+                        println!("Invalid prune age: {}", age);
+                        print_help();
+                        return None;
+                    }
+                }
+            } else if arg == "--dry-run" || arg == "-n" {
+                dry_run = true;
+            } else {
+                println!("Unknown option: {}", arg);
+                print_help();
+                return None;
+            }
+        }
+        return Some(Config {
+            command: Options::Gc { prune, dry_run },
+            args: None,
+        });
+    } else if args[1] == "check-ignore" {
+        let mut verbose = false;
+        let mut paths = vec![];
+        let mut iter = args.get(2..).unwrap().iter();
+        while let Some(arg) = iter.next() {
+            if arg == "--" {
+                paths.extend(iter.cloned());
+                break;
+            } else if arg == "-v" || arg == "--verbose" {
+                verbose = true;
+            } else {
+                paths.push(arg.clone());
+            }
+        }
+        if paths.is_empty() {
+            println!("No paths provided");
+            print_help();
+            return None;
+        }
+        return Some(Config {
+            command: Options::CheckIgnore { verbose },
+            args: Some(paths),
+        });
+    } else if args[1] == "add" {
+        let (jobs, rest) = split_jobs(args.get(2..).unwrap())?;
+        if rest.is_empty() {
+            println!("No paths provided");
+            print_help();
+            return None;
+        } else {
+            return Some(Config {
+                command: Options::Add { jobs },
+                args: Some(rest),
+            });
+        }
+    } else if args[1] == "rm" {
+        let mut cached = false;
+        let mut paths = vec![];
+        let mut iter = args.get(2..).unwrap().iter();
+        while let Some(arg) = iter.next() {
+            if arg == "--" {
+                paths.extend(iter.cloned());
+                break;
+            } else if arg == "--cached" {
+                cached = true;
+            } else {
+                paths.push(arg.clone());
+            }
+        }
+        if paths.is_empty() {
+            println!("No paths provided");
+            print_help();
+            return None;
+        }
+        return Some(Config {
+            command: Options::Rm { cached },
+            args: Some(paths),
+        });
+    } else if args[1] == "restore" {
+        // restores from HEAD unless another commit is given
+        let mut source = String::from("HEAD");
+        let mut paths = vec![];
+        let mut iter = args.get(2..).unwrap().iter();
+        while let Some(arg) = iter.next() {
+            if arg == "--" {
+                paths.extend(iter.cloned());
+                break;
+            } else if let Some(rev) = arg.strip_prefix("--source=") {
+                source = rev.to_string();
+            } else if arg == "--source" || arg == "-s" {
+                match iter.next() {
+                    Some(rev) => source = rev.clone(),
+                    None => {
+                        println!("No source provided");
+                        print_help();
+                        return None;
+                    }
+                }
+            } else {
+                paths.push(arg.clone());
+            }
+        }
+        if paths.is_empty() {
+            println!("No paths provided");
+            print_help();
+            return None;
+        }
+        return Some(Config {
+            command: Options::Restore { source, paths },
+            args: None,
+        });
+    } else if args[1] == "k" {
+        let (abbrev, rest) = split_abbrev(args.get(2..).unwrap())?;
+        if !rest.is_empty() {
+            println!("Too many arguments");
+            print_help();
+            return None;
+        } else {
             return Some(Config {
-                command: Options::K,
-                args: Some(vec![args[2].clone()]),
+                command: Options::K { abbrev },
+                args: None,
             });
         }
     }
@@ -194,6 +405,330 @@ pub fn parse_args(args: &Vec<String>) -> Option<Config> {
     return None;
 }
 
+// takes --abbrev[=<n>] out of args and returns the id length to show (None when not given)
+// along with the remaining args. args after "--" are never taken as options, so a commit
+// message can start with "--"
+fn split_abbrev(args: &[String]) -> Option<(Option<usize>, Vec<String>)> {
+    let mut abbrev = None;
+    let mut rest = vec![];
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            rest.extend(iter.cloned());
+            break;
+        } else if arg == "--abbrev" {
+            abbrev = Some(7);
+        } else if let Some(len) = arg.strip_prefix("--abbrev=") {
+            match len.parse::<usize>() {
+                Ok(x) if (4..=64).contains(&x) => abbrev = Some(x),
+                _ => {
+                    println!("Invalid abbrev length: {}. Expected 4-64", len);
+                    print_help();
+                    return None;
+                }
+            }
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+    return Some((abbrev, rest));
+}
+
+// takes "--jobs=<n>" or "-j <n>" out of args, None when neither was given. args after "--"
+// are never taken as options
+fn split_jobs(args: &[String]) -> Option<(Option<usize>, Vec<String>)> {
+    let mut jobs = None;
+    let mut rest = vec![];
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = if arg == "--" {
+            rest.extend(iter.cloned());
+            break;
+        } else if let Some(x) = arg.strip_prefix("--jobs=") {
+            x
+        } else if arg == "-j" || arg == "--jobs" {
+            match iter.next() {
+                Some(x) => x,
+                None => {
+                    println!("No number of jobs provided");
+                    print_help();
+                    return None;
+                }
+            }
+        } else {
+            rest.push(arg.clone());
+            continue;
+        };
+        match value.parse::<usize>() {
+            Ok(x) if x > 0 => jobs = Some(x),
+            _ => {
+                println!("Invalid number of jobs: {}. Expected at least 1", value);
+                print_help();
+                return None;
+            }
+        }
+    }
+    return Some((jobs, rest));
+}
+
+// parses ages like "now", "never", "90s", "30.minutes" or "2.weeks.ago"
+// returns Some(None) for "never"
+fn parse_age(age: &str) -> Option<Option<Duration>> {
+    match age {
+        "now" => return Some(Some(Duration::ZERO)),
+        "never" => return Some(None),
+        _ => {}
+    }
+    let age = age.strip_suffix(".ago").unwrap_or(age);
+    let split = age.find(|x: char| !x.is_ascii_digit())?;
+    let (count, unit) = age.split_at(split);
+    let count = count.parse::<u64>().ok()?;
+    let seconds = match unit.strip_prefix('.').unwrap_or(unit) {
+        "s" | "second" | "seconds" => 1,
+        "m" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 60 * 60,
+        "d" | "day" | "days" => 24 * 60 * 60,
+        "w" | "week" | "weeks" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    return Some(Some(Duration::from_secs(count.checked_mul(seconds)?)));
+}
+
 fn print_help() {
-    println!("Usage: TODO");
+    println!(
+        "\
+Usage: yeet <command> [<options>] [<args>]
+
+Commands:
+    init                        create an empty repository in the current directory
+    catfile <hash>              print an object
+    hashfile <file>             store a file as a blob and print its id
+    writetree [-j <n>]          store the working tree as a tree and print its id
+    readtree <hash>             check out a tree without moving HEAD
+    setauthor <name>            set the author name used for commits
+    config <key> [<value>]      print or set a config value
+                                keys: {}
+    commit [--abbrev[=<n>]] <message>
+                                commit the index
+    log [--abbrev[=<n>]] [<commit>]
+                                show the history of a commit, HEAD by default
+    checkout [--carry | -f] <commit>
+                                check out a commit or tag and move HEAD to it
+    tag <name> [<commit>]       tag a commit, HEAD by default
+    pack, repack                pack the loose objects
+    fsck                        check every object and ref
+    gc [--prune=<age>] [-n]     remove unreachable objects and pack the rest
+    check-ignore [-v] <path>... print the paths that are ignored
+    add [-j <n>] <path>...      stage paths
+    rm [--cached] <path>...     remove paths from the index and the working tree
+    restore [-s <commit>] <path>...
+                                write paths as they are in a commit, HEAD by default
+    k [--abbrev[=<n>]]          show the commit graph
+
+Options:
+    --abbrev[=<n>]              shorten hashes to n characters (4-64), 7 by default
+    --carry                     checkout: keep local changes, merging them when the file
+                                changed too
+    -f, --force                 checkout: discard local changes
+    --prune=<age>               gc: only remove objects older than age, like \"now\",
+                                \"never\", \"90s\", \"30.minutes\" or \"2.weeks.ago\".
+                                2 weeks by default
+    -n, --dry-run               gc: only list what would be removed
+    --cached                    rm: keep the files in the working tree
+    -v, --verbose               check-ignore: also list paths that are not ignored and
+                                the pattern that decided each path
+    -j, --jobs <n>              hash files on n threads, the jobs config by default
+    -s, --source <commit>       restore: the commit to restore from
+    --                          add, rm, restore, check-ignore, commit and log: take the
+                                arguments after it as paths, messages or commits even
+                                when they start with -",
+        crate::data::CONFIG_KEYS.join(", ")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn commit_message_after_double_dash() {
+        let cases: [(&[&str], &str, Option<usize>); 5] = [
+            (&["msg"], "msg", None),
+            (&["--abbrev", "msg"], "msg", Some(7)),
+            (&["msg", "--abbrev=10"], "msg", Some(10)),
+            (&["--", "--abbrev"], "--abbrev", None),
+            (&["--abbrev=8", "--", "--"], "--", Some(8)),
+        ];
+        for (rest, message, expected) in cases {
+            let config = parse_args(&args(&[&["yeet", "commit"], rest].concat())).unwrap();
+            assert!(
+                matches!(config.command, Options::Commit { abbrev } if abbrev == expected),
+                "{:?}",
+                rest
+            );
+            assert_eq!(config.args.unwrap(), args(&[message]), "{:?}", rest);
+        }
+        let config = parse_args(&args(&["yeet", "log", "--abbrev=12"])).unwrap();
+        assert!(matches!(config.command, Options::Log { abbrev: Some(12) }));
+        assert_eq!(config.args.unwrap(), args(&["HEAD"]));
+        let config = parse_args(&args(&["yeet", "k"])).unwrap();
+        assert!(matches!(config.command, Options::K { abbrev: None }));
+        for bad in ["--abbrev=3", "--abbrev=65", "--abbrev=x"] {
+            assert!(parse_args(&args(&["yeet", "log", bad])).is_none());
+        }
+        assert!(parse_args(&args(&["yeet", "commit", "--abbrev"])).is_none());
+        assert!(parse_args(&args(&["yeet", "commit", "--", "a", "b"])).is_none());
+    }
+
+    #[test]
+    fn jobs() {
+        let cases: [(&[&str], Option<usize>); 4] = [
+            (&[], None),
+            (&["--jobs=3"], Some(3)),
+            (&["-j", "2"], Some(2)),
+            (&["--jobs", "1", "-j", "4"], Some(4)),
+        ];
+        for (flags, expected) in cases {
+            let config = parse_args(&args(&[&["yeet", "writetree"], flags].concat())).unwrap();
+            assert!(matches!(config.command, Options::WriteTree { jobs } if jobs == expected));
+            let config = parse_args(&args(&[&["yeet", "add"], flags, &["a"]].concat())).unwrap();
+            assert!(matches!(config.command, Options::Add { jobs } if jobs == expected));
+            assert_eq!(config.args.unwrap(), args(&["a"]));
+        }
+        for bad in ["--jobs=0", "--jobs=x", "-j"] {
+            assert!(parse_args(&args(&["yeet", "writetree", bad])).is_none());
+        }
+        let config = parse_args(&args(&["yeet", "add", "-j", "2", "--", "-j", "--"])).unwrap();
+        assert!(matches!(config.command, Options::Add { jobs: Some(2) }));
+        assert_eq!(config.args.unwrap(), args(&["-j", "--"]));
+        assert!(parse_args(&args(&["yeet", "add", "--"])).is_none());
+    }
+
+    #[test]
+    fn rm() {
+        let cases: [(&[&str], bool, &[&str]); 4] = [
+            (&["a"], false, &["a"]),
+            (&["--cached", "a", "b"], true, &["a", "b"]),
+            (&["a", "--cached"], true, &["a"]),
+            (
+                &["--cached", "--", "--cached", "-x"],
+                true,
+                &["--cached", "-x"],
+            ),
+        ];
+        for (rest, expected_cached, paths) in cases {
+            let config = parse_args(&args(&[&["yeet", "rm"], rest].concat())).unwrap();
+            assert!(
+                matches!(config.command, Options::Rm { cached } if cached == expected_cached),
+                "{:?}",
+                rest
+            );
+            assert_eq!(config.args.unwrap(), args(paths), "{:?}", rest);
+        }
+        for rest in [&[][..], &["--cached"], &["--"]] {
+            assert!(parse_args(&args(&[&["yeet", "rm"], rest].concat())).is_none());
+        }
+    }
+
+    #[test]
+    fn gc() {
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        let cases: [(&[&str], Option<Duration>, bool); 4] = [
+            (&[], Some(2 * week), false),
+            (&["--prune=now"], Some(Duration::ZERO), false),
+            (&["-n", "--prune=3.days.ago"], Some(week * 3 / 7), true),
+            (&["--prune=never", "--dry-run"], None, true),
+        ];
+        for (flags, expected_prune, expected_dry_run) in cases {
+            let config = parse_args(&args(&[&["yeet", "gc"], flags].concat())).unwrap();
+            assert!(
+                matches!(config.command, Options::Gc { prune, dry_run }
+                    if prune == expected_prune && dry_run == expected_dry_run),
+                "{:?}",
+                flags
+            );
+        }
+        for bad in ["--prune=soon", "--prune=", "path"] {
+            assert!(parse_args(&args(&["yeet", "gc", bad])).is_none());
+        }
+    }
+
+    #[test]
+    fn check_ignore() {
+        let cases: [(&[&str], bool, &[&str]); 4] = [
+            (&["a"], false, &["a"]),
+            (&["-v", "a", "b"], true, &["a", "b"]),
+            (&["a", "--verbose"], true, &["a"]),
+            (&["--", "-v", "--"], false, &["-v", "--"]),
+        ];
+        for (rest, expected_verbose, paths) in cases {
+            let config = parse_args(&args(&[&["yeet", "check-ignore"], rest].concat())).unwrap();
+            assert!(
+                matches!(config.command, Options::CheckIgnore { verbose } if verbose == expected_verbose),
+                "{:?}",
+                rest
+            );
+            assert_eq!(config.args.unwrap(), args(paths), "{:?}", rest);
+        }
+        for rest in [&[][..], &["-v"], &["-v", "--"]] {
+            assert!(parse_args(&args(&[&["yeet", "check-ignore"], rest].concat())).is_none());
+        }
+    }
+
+    #[test]
+    fn restore() {
+        let cases: [(&[&str], &str, &[&str]); 5] = [
+            (&["a"], "HEAD", &["a"]),
+            (&["--source=c", "a", "b"], "c", &["a", "b"]),
+            (&["a", "-s", "c"], "c", &["a"]),
+            (&["--source", "c", "--", "-s", "--"], "c", &["-s", "--"]),
+            (&["--", "--source=c"], "HEAD", &["--source=c"]),
+        ];
+        for (rest, expected_source, expected_paths) in cases {
+            let config = parse_args(&args(&[&["yeet", "restore"], rest].concat())).unwrap();
+            match config.command {
+                Options::Restore { source, paths } => {
+                    assert_eq!(source, expected_source, "{:?}", rest);
+                    assert_eq!(paths, args(expected_paths), "{:?}", rest);
+                }
+                _ => panic!("{:?}", rest),
+            }
+            assert!(config.args.is_none());
+        }
+        for rest in [
+            &[][..],
+            &["-s", "c"],
+            &["a", "--source"],
+            &["-s", "c", "--"],
+        ] {
+            assert!(parse_args(&args(&[&["yeet", "restore"], rest].concat())).is_none());
+        }
+    }
+
+    #[test]
+    fn checkout_modes() {
+        let cases: [(&[&str], CheckoutMode); 5] = [
+            (&["c"], CheckoutMode::Safe),
+            (&["--force", "c"], CheckoutMode::Force),
+            (&["c", "-f"], CheckoutMode::Force),
+            (&["--carry", "c"], CheckoutMode::Carry),
+            (&["--carry", "c", "--carry"], CheckoutMode::Carry),
+        ];
+        for (rest, expected) in cases {
+            let config = parse_args(&args(&[&["yeet", "checkout"], rest].concat())).unwrap();
+            assert!(
+                matches!(config.command, Options::Checkout { mode } if mode == expected),
+                "{:?}",
+                rest
+            );
+            assert_eq!(config.args.unwrap(), args(&["c"]));
+        }
+        for rest in [&["--force", "--carry", "c"][..], &["-f"], &["a", "b"]] {
+            assert!(parse_args(&args(&[&["yeet", "checkout"], rest].concat())).is_none());
+        }
+    }
 }
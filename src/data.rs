@@ -1,50 +1,212 @@
-use std::collections::hash_map::DefaultHasher;
-use std::collections::{HashSet, VecDeque};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::{self, File};
-use std::hash::{Hash, Hasher};
 use std::io::Error as IOError;
 use std::io::ErrorKind as IOErrorKind;
-use std::io::Write;
+use std::io::{self, BufWriter, Read, Write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
 use walkdir::WalkDir;
 
-const SEPARATOR: u8 = 0x00u8;
-#[derive(Debug, PartialEq)]
-pub enum ObjType {
-    Blob,
-    Tree,
-    Commit,
+use crate::chunk;
+use crate::object::{self, CommitData, FileData, FileMode, ObjType, Object};
+use crate::store::{FsStore, ObjectStore};
+
+pub const OBJECTS_DIR: &str = "./.yeet/objects";
+// shortest id prefix that get_actual_hash resolves
+pub const MIN_PREFIX_LEN: usize = 4;
+// layout of the object store, recorded in repo_data/object_layout
+pub const OBJECT_LAYOUT: &str = "fanout";
+// hash algorithm used for object ids, recorded in repo_data/hash_algorithm on init
+pub const HASH_ALGORITHM: &str = "sha256";
+
+// hex encoded sha256 digest of an object
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Ord, PartialOrd)]
+pub struct ObjectId(String);
+
+impl ObjectId {
+    // length of a full id in hex characters
+    pub const HEX_LEN: usize = 64;
+
+    pub fn hash(data: &[u8]) -> ObjectId {
+        let digest = Sha256::digest(data);
+        ObjectId(digest.iter().map(|x| format!("{:02x}", x)).collect())
+    }
+
+    // returns None if s is not a full lowercase hex id
+    pub fn parse(s: &str) -> Option<ObjectId> {
+        if s.len() == Self::HEX_LEN && s.bytes().all(|x| matches!(x, b'0'..=b'9' | b'a'..=b'f')) {
+            return Some(ObjectId(s.to_string()));
+        }
+        None
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // raw digest bytes, used by the pack index
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, x) in bytes.iter_mut().enumerate() {
+            *x = u8::from_str_radix(&self.0[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8; 32]) -> ObjectId {
+        ObjectId(bytes.iter().map(|x| format!("{:02x}", x)).collect())
+    }
 }
 
-pub struct ObjData {
-    pub file_type: Vec<u8>,
-    pub file_data: Vec<u8>,
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// settings that can be changed with `yeet config <key> <value>`
+pub const CONFIG_KEYS: [&str; 3] = ["compression", "compression_level", "jobs"];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Codec {
+    None,
+    Zlib,
 }
 
-pub struct FileData {
-    pub file_name: String,
-    pub file_type: String,
-    pub hash: u64,
+// how new objects are compressed, read from repo_data/compression and repo_data/compression_level
+#[derive(Debug, Clone, Copy)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: u32,
+}
+
+impl Compression {
+    pub const DEFAULT: Compression = Compression {
+        codec: Codec::Zlib,
+        level: 6,
+    };
+
+    fn load() -> Result<Compression, IOError> {
+        let codec = match get_repo_data("compression")? {
+            Some(x) => parse_codec(&x)?,
+            None => Self::DEFAULT.codec,
+        };
+        let level = match get_repo_data("compression_level")? {
+            Some(x) => parse_level(&x)?,
+            None => Self::DEFAULT.level,
+        };
+        return Ok(Compression { codec, level });
+    }
+}
+
+fn parse_codec(codec: &str) -> Result<Codec, IOError> {
+    match codec {
+        "none" => Ok(Codec::None),
+        "zlib" => Ok(Codec::Zlib),
+        _ => Err(IOError::new(
+            IOErrorKind::InvalidInput,
+            format!(
+                "Unknown compression codec: {}. Expected 'zlib' or 'none'",
+                codec
+            ),
+        )),
+    }
+}
+
+fn parse_level(level: &str) -> Result<u32, IOError> {
+    match level.parse::<u32>() {
+        Ok(x) if x <= 9 => Ok(x),
+        _ => Err(IOError::new(
+            IOErrorKind::InvalidInput,
+            format!("Invalid compression level: {}. Expected 0-9", level),
+        )),
+    }
+}
+
+pub fn parse_jobs(jobs: &str) -> Result<usize, IOError> {
+    match jobs.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(x),
+        _ => Err(IOError::new(
+            IOErrorKind::InvalidInput,
+            format!("Invalid number of jobs: {}. Expected at least 1", jobs),
+        )),
+    }
+}
+
+// threads used to hash files, read from repo_data/jobs and one per cpu by default
+pub fn get_jobs() -> Result<usize, IOError> {
+    match get_repo_data("jobs")? {
+        Some(x) => parse_jobs(&x),
+        None => Ok(std::thread::available_parallelism().map_or(1, |x| x.get())),
+    }
+}
+
+// compression settings are read once per run
+pub fn get_compression() -> Result<Compression, IOError> {
+    static COMPRESSION: OnceLock<Compression> = OnceLock::new();
+    if let Some(c) = COMPRESSION.get() {
+        return Ok(*c);
+    }
+    let c = Compression::load()?;
+    return Ok(*COMPRESSION.get_or_init(|| c));
+}
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, IOError> {
+    let compression = get_compression()?;
+    match compression.codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Zlib => {
+            let mut encoder =
+                ZlibEncoder::new(Vec::new(), flate2::Compression::new(compression.level));
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+    }
+}
+
+// uncompressed objects start with their ascii type name while zlib streams start with 0x78,
+// so objects written before compression existed are returned as they are
+pub fn decompress(bytes: Vec<u8>) -> Result<Vec<u8>, IOError> {
+    if bytes.first() != Some(&0x78) {
+        return Ok(bytes);
+    }
+    let mut data = Vec::new();
+    ZlibDecoder::new(bytes.as_slice()).read_to_end(&mut data)?;
+    return Ok(data);
+}
+
+pub struct ObjData {
+    pub file_type: ObjType,
+    pub file_data: Vec<u8>,
 }
 
 pub struct DirEntry {
-    pub name: String,
+    pub name: OsString,
+    pub mode: FileMode,
     pub type_: ObjType,
-    pub hash: String,
+    pub hash: ObjectId,
     pub path: PathBuf,
     pub children: Option<Vec<DirEntry>>,
 }
 
 impl DirEntry {
     fn new(
-        name: String,
+        name: OsString,
+        mode: FileMode,
         type_: ObjType,
-        hash: String,
+        hash: ObjectId,
         path: PathBuf,
         children: Option<Vec<DirEntry>>,
     ) -> DirEntry {
         DirEntry {
             name,
+            mode,
             type_,
             path,
             hash,
@@ -59,157 +221,292 @@ pub struct YeetRef {
     pub ref_data: String,
 }
 pub struct Commit {
-    pub oid: String,
-    pub parent: String,
+    pub oid: ObjectId,
+    // None for the first commit in the repo
+    pub parent: Option<ObjectId>,
 }
 
 // there is probably a better way of doing this
 // iterator that yields all commits that can be reached by following parent chain of given ids
-struct YeetRefIterGen {
-    pub oids: VecDeque<String>,
-    pub visited: HashSet<String>,
+struct YeetRefIterGen<'a> {
+    pub store: &'a dyn ObjectStore,
+    pub oids: VecDeque<ObjectId>,
+    pub visited: HashSet<ObjectId>,
+    // set when the walk stopped early because a commit could not be read
+    pub error: Option<IOError>,
 }
 
-impl Iterator for YeetRefIterGen {
+impl Iterator for YeetRefIterGen<'_> {
     type Item = Commit;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.oids.len() == 0 {
+        if self.oids.is_empty() {
             return None;
         }
 
         let oid = self.oids.pop_front().unwrap();
-        let parent: String;
-        if let Ok(a) = get_commit_parent(&oid) {
-            parent = a;
-        } else {
-            return None;
-        }
-        let is_new_insert = self.visited.insert(parent.clone());
-        if is_new_insert && !self.oids.contains(&parent) && parent != "initial" {
-            self.oids.push_front(parent.clone());
+        let parent = match get_commit_parent(self.store, &oid) {
+            Ok(a) => a,
+            Err(e) => {
+                self.error = Some(e);
+                return None;
+            }
+        };
+        if let Some(p) = &parent {
+            let is_new_insert = self.visited.insert(p.clone());
+            if is_new_insert && !self.oids.contains(p) {
+                self.oids.push_front(p.clone());
+            }
         }
         return Some(Commit { oid, parent });
     }
 }
 
 // makes a single object from vec of FileData objects and writes it as a tree object
-pub fn hash_dir(data: &Vec<FileData>) -> Result<u64, IOError> {
-    let strings = data
-        .iter()
-        .map(|x| {
-            return format!("{} {} {}", x.file_type, x.hash, x.file_name);
-        })
-        .collect::<Vec<String>>()
-        .join("\n");
-
-    return write_obj_hash(strings.as_bytes(), "tree".to_string());
+pub fn hash_dir(store: &dyn ObjectStore, data: &[FileData]) -> Result<ObjectId, IOError> {
+    return write_obj_hash(store, &object::serialize_tree(data), ObjType::Tree);
 }
 
-// writes an objects with given data and type and returns its hash
-pub fn write_obj_hash(data: &[u8], type_: String) -> Result<u64, IOError> {
-    let mut s = DefaultHasher::new();
-    data.hash(&mut s);
-    let hash = s.finish();
+// objects are fanned out into directories named after the first 2 characters of their id
+pub fn object_path(hash: &ObjectId) -> PathBuf {
+    let (dir, file) = hash.as_str().split_at(2);
+    return PathBuf::from(OBJECTS_DIR).join(dir).join(file);
+}
 
-    let buf = [type_.as_bytes(), &[SEPARATOR], data].concat();
+// moves objects from the old flat layout (objects/<id>) into their fan-out directories
+fn migrate_flat_objects() -> Result<usize, IOError> {
+    let mut count = 0;
+    for i in fs::read_dir(OBJECTS_DIR)? {
+        let entry = i?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name();
+        if let Some(hash) = name.to_str().and_then(ObjectId::parse) {
+            let new_path = object_path(&hash);
+            fs::create_dir_all(new_path.parent().unwrap())?;
+            fs::rename(entry.path(), new_path)?;
+            count += 1;
+        }
+    }
+    return Ok(count);
+}
 
-    let mut obj_file = File::create(PathBuf::from("./.yeet/objects").join(hash.to_string()))?;
+// writes an objects with given data and type and returns its hash
+// the hash covers the "<type> <len>" header too so objects of different types never share an id
+pub fn write_obj_hash(
+    store: &dyn ObjectStore,
+    data: &[u8],
+    type_: ObjType,
+) -> Result<ObjectId, IOError> {
+    let buf = object::encode(type_, data);
 
-    obj_file.write_all(buf.as_slice())?;
+    let hash = ObjectId::hash(&buf);
+    store.write(&hash, &buf)?;
 
     return Ok(hash);
 }
 
-// TODO: find better way to do this
-// reads the file_type and data from objects
-pub fn get_data(hash: &String, path_prefix: String) -> Result<ObjData, IOError> {
-    let file_path = PathBuf::from(path_prefix).join(hash);
+// writes data to a temp file next to path, syncs it and renames it over path, so readers
+// (and other yeet processes writing the same file) only ever see a complete file
+pub fn write_atomic(path: &PathBuf, data: &[u8]) -> Result<(), IOError> {
+    return write_atomic_with(path, |file| file.write_all(data));
+}
 
-    let file_bytes = fs::read(&file_path)?;
+// same as write_atomic for files that are written piece by piece
+pub fn write_atomic_with(
+    path: &PathBuf,
+    write: impl FnOnce(&mut File) -> Result<(), IOError>,
+) -> Result<(), IOError> {
+    static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = path.parent().unwrap();
+    let temp_path = dir.join(format!(
+        "tmp_{}_{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
 
-    let mut bytes = file_bytes.split(|&x| x == SEPARATOR);
-    let file_type = bytes.next().unwrap().to_vec();
-    let file_data = bytes.collect::<Vec<_>>().concat();
+    let res = File::create_new(&temp_path)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if let Err(e) = res {
+        _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    // makes the rename itself durable
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
 
+// reads the type and raw payload of an object
+pub fn get_data(store: &dyn ObjectStore, hash: &ObjectId) -> Result<ObjData, IOError> {
+    let (file_type, file_data) = object::decode(hash, store.read(hash)?)?;
     Ok(ObjData {
         file_type,
         file_data,
     })
 }
 
-// reads a tree object from hash and generates vec of files/dirs
-fn decode_dir_data(hash: &String) -> Result<Vec<FileData>, IOError> {
-    let data = get_data(hash, "./.yeet/objects".to_string())?.file_data;
-    let strings = String::from_utf8(data.to_vec()).unwrap();
-    let mut data: Vec<FileData> = Vec::new();
-    for string in strings.split("\n") {
-        let d = string
-            .split_ascii_whitespace()
-            .map(|x| x.to_string())
-            .collect::<Vec<String>>();
-
-        if d.len() != 3 {
+// reads and parses an object
+pub fn read_object(store: &dyn ObjectStore, hash: &ObjectId) -> Result<Object, IOError> {
+    let obj = get_data(store, hash)?;
+    return Object::parse(hash, obj.file_type, obj.file_data);
+}
+
+pub fn write_object(store: &dyn ObjectStore, obj: &Object) -> Result<ObjectId, IOError> {
+    return write_obj_hash(store, &obj.serialize(), obj.type_());
+}
+
+// stores the contents of a file as a single blob, or as chunks plus a chunk list when it is
+// larger than chunk::CHUNK_THRESHOLD, returns the type and id to put in the tree.
+// at most chunk::MAX_CHUNK bytes of the file are held in memory at a time
+pub fn write_file(
+    store: &dyn ObjectStore,
+    reader: &mut dyn Read,
+) -> Result<(ObjType, ObjectId), IOError> {
+    let mut buf: Vec<u8> = Vec::with_capacity(chunk::MAX_CHUNK + 1);
+    fill(reader, &mut buf, chunk::CHUNK_THRESHOLD + 1)?;
+    if buf.len() <= chunk::CHUNK_THRESHOLD {
+        return Ok((ObjType::Blob, write_obj_hash(store, &buf, ObjType::Blob)?));
+    }
+
+    let mut chunks: Vec<ObjectId> = vec![];
+    loop {
+        fill(reader, &mut buf, chunk::MAX_CHUNK)?;
+        if buf.is_empty() {
+            break;
+        }
+        let len = chunk::cut_point(&buf);
+        chunks.push(write_obj_hash(store, &buf[..len], ObjType::Blob)?);
+        buf.drain(..len);
+    }
+    return Ok((
+        ObjType::Chunks,
+        write_object(store, &Object::Chunks(chunks))?,
+    ));
+}
+
+// reads until buf holds len bytes or reader is exhausted
+fn fill(reader: &mut dyn Read, buf: &mut Vec<u8>, len: usize) -> Result<(), IOError> {
+    let missing = len.saturating_sub(buf.len()) as u64;
+    reader.take(missing).read_to_end(buf)?;
+    Ok(())
+}
+
+// writes the contents of a file stored by write_file to out, one chunk at a time
+pub fn copy_file(
+    store: &dyn ObjectStore,
+    hash: &ObjectId,
+    out: &mut dyn Write,
+) -> Result<(), IOError> {
+    let mut reader = store.open(hash)?;
+    let (type_, len) = object::read_header(hash, &mut reader)?;
+    match type_ {
+        ObjType::Blob => return copy_payload(hash, &mut reader, len, out),
+        ObjType::Chunks => {}
+        x => {
             return Err(IOError::new(
                 IOErrorKind::InvalidData,
-                format!("expected 3 values got {}", d.len()),
-            ));
-        } else {
-            let file_type = d[0].clone();
-            let hash = d[1].parse::<u64>().unwrap();
-            let file_name = d[2].clone();
-            if file_type.eq_ignore_ascii_case("tree") && file_type.eq_ignore_ascii_case("blob") {
-                return Err(IOError::new(
-                    IOErrorKind::InvalidData,
-                    format!(
-                        "Incorrect type found: {}. Expected 'tree' or 'blob', {:?}",
-                        file_type, d
-                    ),
-                ));
-            }
+                format!("Object {} is a {}, not a file", hash, x),
+            ))
+        }
+    }
 
-            let file_d = FileData {
-                file_name,
-                file_type,
-                hash,
-            };
-            data.push(file_d);
+    // the chunk list itself is small, 65 bytes per chunk
+    let mut list: Vec<u8> = vec![];
+    copy_payload(hash, &mut reader, len, &mut list)?;
+    let ids = match Object::parse(hash, type_, list)? {
+        Object::Chunks(ids) => ids,
+        _ => unreachable!(),
+    };
+    for id in ids {
+        let mut reader = store.open(&id)?;
+        let (type_, len) = object::read_header(&id, &mut reader)?;
+        if type_ != ObjType::Blob {
+            return Err(IOError::new(
+                IOErrorKind::InvalidData,
+                format!("Chunk {} of {} is a {}, not a blob", id, hash, type_),
+            ));
         }
+        copy_payload(&id, &mut reader, len, out)?;
+    }
+    Ok(())
+}
+
+fn copy_payload(
+    hash: &ObjectId,
+    reader: &mut dyn Read,
+    len: usize,
+    out: &mut dyn Write,
+) -> Result<(), IOError> {
+    let copied = io::copy(&mut reader.take(len as u64), out)?;
+    if copied != len as u64 {
+        return Err(IOError::new(
+            IOErrorKind::InvalidData,
+            format!(
+                "Object {} has length {} but its header says {}",
+                hash, copied, len
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// reads a tree object from hash and generates vec of files/dirs
+pub fn decode_dir_data(store: &dyn ObjectStore, hash: &ObjectId) -> Result<Vec<FileData>, IOError> {
+    match read_object(store, hash)? {
+        Object::Tree(entries) => Ok(entries),
+        x => Err(IOError::new(
+            IOErrorKind::InvalidData,
+            format!("Object {} is a {}, not a tree", hash, x.type_()),
+        )),
+    }
+}
+
+// reads a commit object
+pub fn read_commit_data(
+    store: &dyn ObjectStore,
+    commit_id: &ObjectId,
+) -> Result<CommitData, IOError> {
+    match read_object(store, commit_id)? {
+        Object::Commit(c) => Ok(c),
+        x => Err(IOError::new(
+            IOErrorKind::InvalidData,
+            format!("Object {} is a {}, not a commit", commit_id, x.type_()),
+        )),
     }
-    return Ok(data);
 }
 
 // recursively generates tree of all files from the given hash
-pub fn gen_tree(hash: String, name: String, path: PathBuf) -> Result<DirEntry, IOError> {
-    let actual_hash = get_actual_hash(&hash)?;
-    let dir_data = decode_dir_data(&actual_hash)?;
+pub fn gen_tree(
+    store: &dyn ObjectStore,
+    hash: String,
+    name: OsString,
+    path: PathBuf,
+) -> Result<DirEntry, IOError> {
+    let actual_hash = get_actual_hash(store, &hash)?;
+    let dir_data = decode_dir_data(store, &actual_hash)?;
     if dir_data.is_empty() {
-        return Ok(DirEntry::new(name, ObjType::Tree, actual_hash, path, None));
+        return Ok(DirEntry::new(
+            name,
+            FileMode::Directory,
+            ObjType::Tree,
+            actual_hash,
+            path,
+            None,
+        ));
     }
 
     let children = dir_data
         .iter()
-        .map(|x| {
-            if x.file_type == "tree".to_string() {
-                gen_tree(
-                    x.hash.to_string(),
-                    x.file_name.clone(),
-                    path.join(x.file_name.clone()),
-                )
-                .unwrap()
-            } else {
-                return DirEntry::new(
-                    x.file_name.clone(),
-                    ObjType::Blob,
-                    x.hash.to_string(),
-                    path.join(x.file_name.clone()),
-                    None,
-                );
-            }
-        })
-        .collect::<Vec<DirEntry>>();
+        .map(|x| gen_entry(store, x, path.join(&x.file_name)))
+        .collect::<Result<Vec<DirEntry>, IOError>>()?;
 
     return Ok(DirEntry::new(
         name,
+        FileMode::Directory,
         ObjType::Tree,
         actual_hash,
         path,
@@ -217,141 +514,325 @@ pub fn gen_tree(hash: String, name: String, path: PathBuf) -> Result<DirEntry, I
     ));
 }
 
+// generates the entry for one line of a tree stored at path, with all files under it
+pub fn gen_entry(
+    store: &dyn ObjectStore,
+    data: &FileData,
+    path: PathBuf,
+) -> Result<DirEntry, IOError> {
+    if data.file_type == ObjType::Tree {
+        return gen_tree(store, data.hash.to_string(), data.file_name.clone(), path);
+    }
+    return Ok(DirEntry::new(
+        data.file_name.clone(),
+        data.mode,
+        data.file_type,
+        data.hash.clone(),
+        path,
+        None,
+    ));
+}
+
 // prints the tree generated from gen_tree
 pub fn show_tree(entry: &DirEntry, count: usize) {
     let padding = String::from("\t").repeat(count);
-    println!("{}Name: {}", padding, entry.name);
+    println!("{}Name: {:?}", padding, entry.name);
     println!("{}Type: {:?}", padding, entry.type_);
     println!("{}Path: {:?}", padding, entry.path);
     if let Some(children) = &entry.children {
         println!("{}children: ", padding);
         for i in children {
-            show_tree(&i, count + 1);
+            show_tree(i, count + 1);
         }
     }
 }
 
 // writes given direntry to the file system
-pub fn write_entry(entry: DirEntry) {
-    if entry.type_ == ObjType::Blob {
-        let file_data = get_data(&entry.hash, String::from("./.yeet/objects/"))
-            .unwrap()
-            .file_data;
-        let mut file = fs::File::create(entry.path).unwrap();
-        file.write_all(file_data.as_slice()).unwrap();
-    } else {
-        fs::create_dir_all(entry.path).unwrap();
-        if let Some(children) = entry.children {
-            for i in children {
-                write_entry(i);
-            }
+pub fn write_entry(store: &dyn ObjectStore, entry: &DirEntry) -> Result<(), IOError> {
+    if entry.type_ != ObjType::Tree {
+        return write_blob(store, entry.mode, &entry.hash, &entry.path);
+    }
+    fs::create_dir_all(&entry.path)?;
+    if let Some(children) = &entry.children {
+        for i in children {
+            write_entry(store, i)?;
         }
     }
+    Ok(())
 }
 
-// reads and prints info of a single commit
-fn read_commit(hash: String) -> Result<(), IOError> {
-    let actual_hash = get_actual_hash(&hash)?;
-    if actual_hash == "initial" {
-        return Err(IOError::new(
-            IOErrorKind::Other,
-            "No commits in current repo",
-        ));
+// writes a file or symlink to path, replacing the file or symlink already there. the
+// directories above it are created as needed
+pub fn write_blob(
+    store: &dyn ObjectStore,
+    mode: FileMode,
+    hash: &ObjectId,
+    path: &PathBuf,
+) -> Result<(), IOError> {
+    // a new file is needed to drop the execute bits or to turn a symlink into a file
+    match fs::symlink_metadata(path) {
+        Ok(x) if !x.is_dir() => fs::remove_file(path)?,
+        Err(e) if e.kind() != IOErrorKind::NotFound => return Err(e),
+        _ => {}
     }
-
-    let commit_data = get_data(&actual_hash, "./.yeet/objects".to_string()).unwrap();
-    if String::from_utf8(commit_data.file_type).unwrap() != "commit" {
-        return Err(IOError::new(
-            IOErrorKind::InvalidData,
-            format!("Invalid commit or tag found {}", actual_hash),
-        ));
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    if mode == FileMode::Symlink {
+        let mut target: Vec<u8> = vec![];
+        copy_file(store, hash, &mut target)?;
+        return make_symlink(&target, path);
+    }
+    let mut file = BufWriter::new(fs::File::create(path)?);
+    copy_file(store, hash, &mut file)?;
+    file.flush()?;
+    if mode == FileMode::Executable {
+        set_executable(path)?;
     }
-    let strings = String::from_utf8(commit_data.file_data).unwrap();
-    let mut data = strings.split("\n").map(|x| x.to_string());
-
-    // TODO: fix this iter monster
-    let _commit_tree = data.next().unwrap().split_once(" ").unwrap().1.to_string();
-    let _parent_hash = data.next().unwrap().split_once(" ").unwrap().1.to_string();
-    let author_name = data.next().unwrap().split_once(" ").unwrap().1.to_string();
-    let time = data.next().unwrap().split_once(" ").unwrap().1.to_string();
-    let message = data.collect::<Vec<_>>().concat();
-
-    println!("commit {}", actual_hash);
-    println!("Author: {}", author_name);
-    println!("Date: {}", time);
-    println!("{}", message);
-    print!("\n");
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    return metadata.permissions().mode() & 0o111 != 0;
+}
+
+#[cfg(not(unix))]
+pub fn is_executable(_metadata: &fs::Metadata) -> bool {
+    return false;
+}
+
+// adds execute permission wherever the file already has read permission, like chmod +x
+#[cfg(unix)]
+fn set_executable(path: &PathBuf) -> Result<(), IOError> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | (mode & 0o444) >> 2);
+    return fs::set_permissions(path, permissions);
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &PathBuf) -> Result<(), IOError> {
+    Ok(())
+}
+
+// the target of a symlink as it is stored in its blob
+#[cfg(unix)]
+pub fn read_link_bytes(path: &PathBuf) -> Result<Vec<u8>, IOError> {
+    use std::os::unix::ffi::OsStrExt;
+    return Ok(fs::read_link(path)?.as_os_str().as_bytes().to_vec());
+}
+
+#[cfg(not(unix))]
+pub fn read_link_bytes(path: &PathBuf) -> Result<Vec<u8>, IOError> {
+    return Ok(fs::read_link(path)?.to_string_lossy().as_bytes().to_vec());
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &[u8], path: &PathBuf) -> Result<(), IOError> {
+    use std::os::unix::ffi::OsStrExt;
+    return std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path);
+}
+
+// without symlinks the link becomes a plain file holding its target
+#[cfg(not(unix))]
+fn make_symlink(target: &[u8], path: &PathBuf) -> Result<(), IOError> {
+    return fs::write(path, target);
+}
+
+// reads and prints info of a single commit
+fn read_commit(
+    store: &dyn ObjectStore,
+    actual_hash: &ObjectId,
+    abbrev: Option<usize>,
+) -> Result<(), IOError> {
+    let commit = read_commit_data(store, actual_hash)?;
+
+    println!("commit {}", abbreviate(store, actual_hash, abbrev)?);
+    println!("Author: {}", commit.author);
+    println!("Date: {}", commit.time);
+    println!("{}", commit.message);
+    println!();
     Ok(())
 }
 
 // writes info of all commits in the parent tree of commit with given hash
-pub fn log(hash: String) -> Result<(), IOError> {
-    let oids = VecDeque::from([hash]);
-    let mut visited: HashSet<String> = HashSet::new();
+pub fn log(store: &dyn ObjectStore, hash: String, abbrev: Option<usize>) -> Result<(), IOError> {
+    let oids = VecDeque::from([get_actual_hash(store, &hash)?]);
+    let mut visited: HashSet<ObjectId> = HashSet::new();
 
     oids.iter().for_each(|x| {
         visited.insert(x.clone());
     });
-    let iter_gen = YeetRefIterGen { oids, visited };
+    let iter_gen = YeetRefIterGen {
+        store,
+        oids,
+        visited,
+        error: None,
+    };
 
     for i in iter_gen.into_iter() {
-        read_commit(i.oid)?;
+        read_commit(store, &i.oid, abbrev)?;
     }
     Ok(())
 }
 
 // returns the tree hash from a given commit
-pub fn get_commit_tree(hash: &String) -> Result<String, IOError> {
-    let actual_hash = get_actual_hash(hash)?;
-    let commit_data = get_data(&actual_hash, "./.yeet/objects".to_string())?;
-    if String::from_utf8(commit_data.file_type).unwrap() != "commit" {
-        return Err(IOError::new(
-            IOErrorKind::InvalidData,
-            format!("Invalid commit or hash : {}", hash),
-        ));
+pub fn get_commit_tree(store: &dyn ObjectStore, hash: &String) -> Result<ObjectId, IOError> {
+    let actual_hash = get_actual_hash(store, hash)?;
+    return Ok(read_commit_data(store, &actual_hash)?.tree);
+}
+
+// returns hash of parent commit or None for the first commit
+pub fn get_commit_parent(
+    store: &dyn ObjectStore,
+    commit_id: &ObjectId,
+) -> Result<Option<ObjectId>, IOError> {
+    return Ok(read_commit_data(store, commit_id)?.parent);
+}
+
+// ids of every object stored in its own file under the fan-out directories
+pub fn loose_object_ids() -> Result<Vec<ObjectId>, IOError> {
+    let mut ids: Vec<ObjectId> = vec![];
+    for i in fs::read_dir(OBJECTS_DIR)? {
+        let dir = i?;
+        let dir_name = dir.file_name();
+        let dir_name = dir_name.to_string_lossy();
+        if dir_name.len() != 2 || !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for j in fs::read_dir(dir.path())? {
+            let file_name = j?.file_name();
+            if let Some(id) =
+                ObjectId::parse(&format!("{}{}", dir_name, file_name.to_string_lossy()))
+            {
+                ids.push(id);
+            }
+        }
     }
-    let strings = String::from_utf8(commit_data.file_data).unwrap();
-    let mut commit_data = strings.split("\n").map(|x| x.to_string());
-    let tree_hash = commit_data
-        .next()
-        .unwrap()
-        .split_once(" ")
-        .unwrap()
-        .1
-        .to_string();
-    return Ok(tree_hash);
-}
-
-// returns hash of parent commit
-fn get_commit_parent(commit_id: &String) -> Result<String, IOError> {
-    let commit_id = get_actual_hash(&commit_id)?;
-    let commit_data = get_data(&commit_id, "./.yeet/objects".to_string()).unwrap();
-    if String::from_utf8(commit_data.file_type).unwrap() != "commit" {
-        return Err(IOError::new(
-            IOErrorKind::InvalidData,
-            format!("Invalid commit or tag found {}", commit_id),
-        ));
+    return Ok(ids);
+}
+
+// an object found by reachable_objects, name is the file name a blob or tree was first seen under
+pub struct ReachableObject {
+    pub id: ObjectId,
+    pub type_: ObjType,
+    pub name: OsString,
+}
+
+// walks every commit reachable from refs like print_all_refs and then descends into their trees
+pub fn reachable_objects(store: &dyn ObjectStore) -> Result<Vec<ReachableObject>, IOError> {
+    let mut oids: VecDeque<ObjectId> = VecDeque::new();
+    for yeet_ref in get_all_refs()? {
+        if let Some(id) = ObjectId::parse(&yeet_ref.ref_data) {
+            if !oids.contains(&id) {
+                oids.push_back(id);
+            }
+        }
     }
-    let strings = String::from_utf8(commit_data.file_data).unwrap();
-    let mut data = strings.split("\n").map(|x| x.to_string());
+    let visited = oids.iter().cloned().collect::<HashSet<ObjectId>>();
+    let mut iter_gen = YeetRefIterGen {
+        store,
+        oids,
+        visited,
+        error: None,
+    };
 
-    // TODO: fix this iter monster
-    let _ = data.next().unwrap().split_once(" ").unwrap().1.to_string();
-    let parent_hash = data.next().unwrap().split_once(" ").unwrap().1.to_string();
-    Ok(parent_hash)
+    let mut seen: HashSet<ObjectId> = HashSet::new();
+    let mut objects: Vec<ReachableObject> = vec![];
+    for i in iter_gen.by_ref() {
+        let tree = get_commit_tree(store, &i.oid.to_string())?;
+        objects.push(ReachableObject {
+            id: i.oid,
+            type_: ObjType::Commit,
+            name: OsString::new(),
+        });
+        if seen.insert(tree.clone()) {
+            reachable_tree_objects(store, tree, OsString::new(), &mut seen, &mut objects)?;
+        }
+    }
+    // a partial walk would make reachable objects look unreachable
+    if let Some(e) = iter_gen.error {
+        return Err(e);
+    }
+    return Ok(objects);
+}
+
+fn reachable_tree_objects(
+    store: &dyn ObjectStore,
+    hash: ObjectId,
+    name: OsString,
+    seen: &mut HashSet<ObjectId>,
+    objects: &mut Vec<ReachableObject>,
+) -> Result<(), IOError> {
+    let dir_data = decode_dir_data(store, &hash)?;
+    objects.push(ReachableObject {
+        id: hash,
+        type_: ObjType::Tree,
+        name,
+    });
+    for x in dir_data {
+        if !seen.insert(x.hash.clone()) {
+            continue;
+        }
+        match x.file_type {
+            ObjType::Tree => {
+                reachable_tree_objects(store, x.hash, x.file_name, seen, objects)?;
+            }
+            ObjType::Chunks => {
+                let chunks = match read_object(store, &x.hash)? {
+                    Object::Chunks(ids) => ids,
+                    o => {
+                        return Err(IOError::new(
+                            IOErrorKind::InvalidData,
+                            format!("Object {} is a {}, not chunks", x.hash, o.type_()),
+                        ))
+                    }
+                };
+                objects.push(ReachableObject {
+                    id: x.hash,
+                    type_: ObjType::Chunks,
+                    name: x.file_name.clone(),
+                });
+                for id in chunks {
+                    if seen.insert(id.clone()) {
+                        objects.push(ReachableObject {
+                            id,
+                            type_: ObjType::Blob,
+                            name: x.file_name.clone(),
+                        });
+                    }
+                }
+            }
+            _ => objects.push(ReachableObject {
+                id: x.hash,
+                type_: ObjType::Blob,
+                name: x.file_name,
+            }),
+        }
+    }
+    Ok(())
 }
 
 // returns data from existing tag
 pub fn get_ref(ref_name: &String, ref_path: PathBuf) -> Result<String, IOError> {
-    let id = fs::read_to_string(PathBuf::from("./.yeet/refs/").join(ref_path).join(&ref_name));
+    let id = fs::read_to_string(PathBuf::from("./.yeet/refs/").join(ref_path).join(ref_name));
     return id;
 }
 
 // sets a new tag with data (hash)
-pub fn set_ref(ref_name: String, hash: String, ref_path: PathBuf) -> Result<(), IOError> {
-    let actual_hash = get_actual_hash(&hash)?;
-    let type_ = get_data(&actual_hash, "./.yeet/objects".to_string())?.file_type;
-    if String::from_utf8(type_).unwrap() != "commit" {
+pub fn set_ref(
+    store: &dyn ObjectStore,
+    ref_name: String,
+    hash: String,
+    ref_path: PathBuf,
+) -> Result<(), IOError> {
+    let actual_hash = get_actual_hash(store, &hash)?;
+    if get_data(store, &actual_hash)?.file_type != ObjType::Commit {
         return Err(IOError::new(
             IOErrorKind::InvalidData,
             "Invalid commit object found",
@@ -361,35 +842,296 @@ pub fn set_ref(ref_name: String, hash: String, ref_path: PathBuf) -> Result<(),
     let tag_path = PathBuf::from("./.yeet/refs").join(ref_path).join(&ref_name);
     let mut tag_file = fs::File::create(tag_path)?;
 
-    tag_file.write(actual_hash.as_bytes())?;
+    tag_file.write_all(actual_hash.as_str().as_bytes())?;
 
     Ok(())
 }
 
-// if input was not u64 then attempts to read the hash by treating input as a tag
-fn get_actual_hash(hash: &String) -> Result<String, IOError> {
-    if let Err(_) = hash.parse::<u64>() {
-        let actual_hash: String;
-        if hash == "HEAD" {
-            actual_hash = get_ref(&hash, PathBuf::new())?;
+// resolves a full object id, HEAD, a tag name or a unique id prefix of at least
+// MIN_PREFIX_LEN characters
+pub fn get_actual_hash(store: &dyn ObjectStore, hash: &String) -> Result<ObjectId, IOError> {
+    if let Some(id) = ObjectId::parse(hash) {
+        return Ok(id);
+    }
+    let actual_hash = if hash == "HEAD" {
+        get_ref(hash, PathBuf::new())
+    } else {
+        get_ref(hash, PathBuf::from("tags"))
+    };
+    let actual_hash = match actual_hash {
+        Ok(x) => x,
+        Err(e) if e.kind() == IOErrorKind::NotFound && is_id_prefix(hash) => {
+            return resolve_prefix(store, hash);
+        }
+        Err(e) if e.kind() == IOErrorKind::NotFound => {
+            return Err(IOError::new(
+                IOErrorKind::NotFound,
+                format!("Unknown revision: {}", hash),
+            ));
+        }
+        Err(e) => return Err(e),
+    };
+    if actual_hash == "initial" {
+        return Err(IOError::new(
+            IOErrorKind::InvalidData,
+            "No commits found in the repo",
+        ));
+    }
+    return ObjectId::parse(&actual_hash).ok_or(IOError::new(
+        IOErrorKind::InvalidData,
+        format!("Ref {} holds an invalid object id: {}", hash, actual_hash),
+    ));
+}
+
+fn is_id_prefix(prefix: &str) -> bool {
+    prefix.len() >= MIN_PREFIX_LEN
+        && prefix.len() <= ObjectId::HEX_LEN
+        && prefix
+            .bytes()
+            .all(|x| matches!(x, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn resolve_prefix(store: &dyn ObjectStore, prefix: &str) -> Result<ObjectId, IOError> {
+    let mut ids = store.find_prefix(prefix)?;
+    match ids.len() {
+        0 => Err(IOError::new(
+            IOErrorKind::NotFound,
+            format!("Unknown revision: {}", prefix),
+        )),
+        1 => Ok(ids.remove(0)),
+        _ => Err(IOError::new(
+            IOErrorKind::InvalidInput,
+            format!(
+                "Ambiguous prefix {}, candidates are:\n{}",
+                prefix,
+                ids.iter()
+                    .map(|x| x.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+        )),
+    }
+}
+
+// shortest unique prefix of id that is at least len characters long, None shows the full id
+pub fn abbreviate(
+    store: &dyn ObjectStore,
+    id: &ObjectId,
+    len: Option<usize>,
+) -> Result<String, IOError> {
+    let mut len = match len {
+        Some(x) => x.clamp(MIN_PREFIX_LEN, ObjectId::HEX_LEN),
+        None => return Ok(id.to_string()),
+    };
+    let others = store.find_prefix(&id.as_str()[..len])?;
+    while len < ObjectId::HEX_LEN
+        && others
+            .iter()
+            .any(|x| x != id && x.as_str()[..len] == id.as_str()[..len])
+    {
+        len += 1;
+    }
+    return Ok(id.as_str()[..len].to_string());
+}
+
+// reads a setting from repo_data, None if it was never set
+pub fn get_repo_data(key: &str) -> Result<Option<String>, IOError> {
+    match fs::read_to_string(PathBuf::from("./.yeet/repo_data").join(key)) {
+        Ok(x) => Ok(Some(x.trim().to_string())),
+        Err(e) if e.kind() == IOErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// validates and stores one of CONFIG_KEYS in repo_data
+pub fn set_config(key: &str, value: &str) -> Result<(), IOError> {
+    match key {
+        "compression" => {
+            parse_codec(value)?;
+        }
+        "compression_level" => {
+            parse_level(value)?;
         }
-        else {
-            actual_hash = get_ref(&hash, PathBuf::from("tags"))?;
+        "jobs" => {
+            parse_jobs(value)?;
         }
-        if actual_hash == "initial" {
+        _ => {
             return Err(IOError::new(
-                IOErrorKind::InvalidData,
-                "No commits found in the repo",
+                IOErrorKind::InvalidInput,
+                format!(
+                    "Unknown config key: {}. Expected one of {:?}",
+                    key, CONFIG_KEYS
+                ),
             ));
         }
-        return Ok(actual_hash);
-    } else {
-        return Ok(hash.clone());
     }
+    fs::write(PathBuf::from("./.yeet/repo_data").join(key), value)?;
+    Ok(())
+}
+
+// makes sure the repo names its objects with the same algorithm as this build. repos created
+// before hash_algorithm was recorded are converted to it
+fn check_hash_algorithm() -> Result<(), IOError> {
+    let algorithm = match fs::read_to_string("./.yeet/repo_data/hash_algorithm") {
+        Ok(a) => a,
+        Err(e) if e.kind() == IOErrorKind::NotFound => {
+            if !PathBuf::from("./.yeet").is_dir() {
+                return Err(IOError::new(IOErrorKind::NotFound, "Not a yeet repo"));
+            }
+            let count = migrate_u64_objects(&FsStore)?;
+            fs::write("./.yeet/repo_data/hash_algorithm", HASH_ALGORITHM)?;
+            println!("Converted {} objects to {} ids", count, HASH_ALGORITHM);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    if algorithm.trim() != HASH_ALGORITHM {
+        return Err(IOError::new(
+            IOErrorKind::Unsupported,
+            format!(
+                "Repo uses unsupported hash algorithm: {} (expected {})",
+                algorithm.trim(),
+                HASH_ALGORITHM
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// the first repos named objects after the 64 bit DefaultHasher value of their payload and
+// stored them as "<type>\0<payload>" in objects/<decimal id>. every such object is written
+// again under its sha256 id, refs are pointed at the new ids and the old files are removed
+fn migrate_u64_objects(store: &dyn ObjectStore) -> Result<usize, IOError> {
+    let mut old_ids = vec![];
+    for i in fs::read_dir(OBJECTS_DIR)? {
+        let entry = i?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|x| x.parse::<u64>().ok())
+        {
+            old_ids.push(id);
+        }
+    }
+
+    let mut new_ids: HashMap<u64, ObjectId> = HashMap::new();
+    for id in &old_ids {
+        migrate_u64_object(store, *id, &mut new_ids)?;
+    }
+    for i in WalkDir::new("./.yeet/refs") {
+        let entry = i?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        // HEAD holds "initial" until the first commit
+        let old = fs::read_to_string(entry.path())?;
+        if let Some(new) = old.trim().parse::<u64>().ok().and_then(|x| new_ids.get(&x)) {
+            fs::write(entry.path(), new.as_str())?;
+        }
+    }
+    for id in &old_ids {
+        fs::remove_file(PathBuf::from(OBJECTS_DIR).join(id.to_string()))?;
+    }
+    return Ok(old_ids.len());
+}
+
+// converts one old object, after the objects it points at
+fn migrate_u64_object(
+    store: &dyn ObjectStore,
+    old: u64,
+    new_ids: &mut HashMap<u64, ObjectId>,
+) -> Result<ObjectId, IOError> {
+    if let Some(id) = new_ids.get(&old) {
+        return Ok(id.clone());
+    }
+    let bad_object = || {
+        IOError::new(
+            IOErrorKind::InvalidData,
+            format!("Invalid object {} in old repo", old),
+        )
+    };
+    let bytes = fs::read(PathBuf::from(OBJECTS_DIR).join(old.to_string()))?;
+    // only the first separator ends the type, payloads may contain more of them
+    let header_end = bytes.iter().position(|&x| x == 0).ok_or_else(bad_object)?;
+    let payload = &bytes[header_end + 1..];
+    let text = || std::str::from_utf8(payload).map_err(|_| bad_object());
+
+    let object = match &bytes[..header_end] {
+        b"blob" => Object::Blob(payload.to_vec()),
+        // "<type> <id> <name>" lines, old trees had no modes
+        b"tree" => {
+            let mut entries = vec![];
+            for line in text()?.split('\n').filter(|x| !x.is_empty()) {
+                let d = line.splitn(3, ' ').collect::<Vec<&str>>();
+                if d.len() != 3 {
+                    return Err(bad_object());
+                }
+                let (mode, file_type) = match d[0] {
+                    "blob" => (FileMode::Regular, ObjType::Blob),
+                    "tree" => (FileMode::Directory, ObjType::Tree),
+                    _ => return Err(bad_object()),
+                };
+                let id = d[1].parse::<u64>().map_err(|_| bad_object())?;
+                entries.push(FileData {
+                    file_name: OsString::from(d[2]),
+                    mode,
+                    file_type,
+                    hash: migrate_u64_object(store, id, new_ids)?,
+                });
+            }
+            Object::Tree(entries)
+        }
+        b"commit" => {
+            let d = text()?.splitn(5, '\n').collect::<Vec<&str>>();
+            let field = |i: usize, key: &str| {
+                d.get(i)
+                    .and_then(|x| x.strip_prefix(key))
+                    .ok_or_else(bad_object)
+            };
+            let tree = field(0, "tree ")?
+                .parse::<u64>()
+                .map_err(|_| bad_object())?;
+            let parent = match field(1, "parent ")? {
+                "initial" => None,
+                x => Some(x.parse::<u64>().map_err(|_| bad_object())?),
+            };
+            let author = field(2, "author ")?.to_string();
+            let time = field(3, "time ")?.to_string();
+            let message = d.get(4).unwrap_or(&"").to_string();
+            Object::Commit(CommitData {
+                tree: migrate_u64_object(store, tree, new_ids)?,
+                parent: match parent {
+                    Some(x) => Some(migrate_u64_object(store, x, new_ids)?),
+                    None => None,
+                },
+                author,
+                time,
+                message,
+            })
+        }
+        _ => return Err(bad_object()),
+    };
+    let id = write_object(store, &object)?;
+    new_ids.insert(old, id.clone());
+    return Ok(id);
+}
+
+// checks that the repo can be used by this build and migrates the object store if needed
+pub fn open_repo() -> Result<(), IOError> {
+    check_hash_algorithm()?;
+    if get_repo_data("object_layout")?.as_deref() != Some(OBJECT_LAYOUT) {
+        let count = migrate_flat_objects()?;
+        fs::write("./.yeet/repo_data/object_layout", OBJECT_LAYOUT)?;
+        println!("Moved {} objects to the {} layout", count, OBJECT_LAYOUT);
+    }
+    Ok(())
 }
 
 // reads all saved refs
-fn get_all_refs() -> Result<Vec<YeetRef>, IOError> {
+pub fn get_all_refs() -> Result<Vec<YeetRef>, IOError> {
     let mut refs: Vec<YeetRef> = vec![];
     for i in WalkDir::new("./.yeet/refs/") {
         let entry = i?;
@@ -411,14 +1153,17 @@ fn get_all_refs() -> Result<Vec<YeetRef>, IOError> {
 
 // generates graph from refs read by get_all_refs()
 // https://graphviz.org/doc/info/lang.html
-pub fn print_all_refs() -> Result<(), IOError> {
+pub fn print_all_refs(store: &dyn ObjectStore, abbrev: Option<usize>) -> Result<(), IOError> {
     let refs = get_all_refs()?;
-    let mut oids: VecDeque<String> = VecDeque::new();
+    let mut oids: VecDeque<ObjectId> = VecDeque::new();
     let mut dot = String::from("digraph commits {\n");
     for yeet_ref in refs {
-        oids.push_back(yeet_ref.ref_data.clone());
+        // HEAD holds "initial" until the first commit
+        if let Some(id) = ObjectId::parse(&yeet_ref.ref_data) {
+            oids.push_back(id);
+        }
         dot += format!(
-            "{} [shape=note]\n{} -> {}\n",
+            "{} [shape=note]\n{} -> \"{}\"\n",
             yeet_ref.ref_name, yeet_ref.ref_name, yeet_ref.ref_data
         )
         .as_str();
@@ -429,13 +1174,276 @@ pub fn print_all_refs() -> Result<(), IOError> {
         visited.insert(x.clone());
     });
 
-    let iter_gen = YeetRefIterGen { oids, visited };
+    let iter_gen = YeetRefIterGen {
+        store,
+        oids,
+        visited,
+        error: None,
+    };
 
     for i in iter_gen.into_iter() {
-        dot += format!("{} [shape=box style=filled label={}]\n", i.oid, i.oid).as_str();
-        dot += format!("{} -> {}\n", i.oid, i.parent).as_str();
+        let parent = match &i.parent {
+            Some(p) => p.to_string(),
+            None => "initial".to_string(),
+        };
+        dot += format!(
+            "\"{}\" [shape=box style=filled label=\"{}\"]\n",
+            i.oid,
+            abbreviate(store, &i.oid, abbrev)?
+        )
+        .as_str();
+        dot += format!("\"{}\" -> \"{}\"\n", i.oid, parent).as_str();
     }
     dot += "}";
     println!("{}", dot);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::testutil::{TempDir, TempRepo};
+    use crate::yeet;
+
+    #[test]
+    fn binary_files_round_trip() {
+        let dir = TempDir::new();
+        let store = MemoryStore::new();
+        // large enough to be split into chunks
+        let mut x: u32 = 1;
+        let large = (0..chunk::CHUNK_THRESHOLD + 12345)
+            .map(|_| {
+                x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+                (x >> 24) as u8
+            })
+            .collect::<Vec<u8>>();
+        let files: [(&str, &[u8]); 4] = [
+            ("empty", b""),
+            ("nul", b"\0\0a\0b\n\0"),
+            ("not_utf8", b"\xff\xfe\xc3\x28 \x80\0\n\r\n"),
+            ("large", &large),
+        ];
+        for (name, contents) in files {
+            let path = dir.path.join(name);
+            fs::write(&path, contents).unwrap();
+            let (type_, hash) = yeet::hash_file(&store, path.clone(), false).unwrap();
+            let expected = if contents.len() > chunk::CHUNK_THRESHOLD {
+                ObjType::Chunks
+            } else {
+                ObjType::Blob
+            };
+            assert_eq!(type_, expected, "{}", name);
+
+            let out = dir.path.join(format!("{}.out", name));
+            write_blob(&store, FileMode::Regular, &hash, &out).unwrap();
+            assert_eq!(fs::read(&out).unwrap(), contents, "{}", name);
+        }
+    }
+
+    #[test]
+    fn uncompressed_objects_are_still_read() {
+        let _repo = TempRepo::new();
+        for payload in [b"old".as_slice(), b"new"] {
+            let bytes = object::encode(ObjType::Blob, payload);
+            let id = ObjectId::hash(&bytes);
+            let stored = if payload == b"old" {
+                bytes.clone()
+            } else {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&bytes).unwrap();
+                encoder.finish().unwrap()
+            };
+            fs::create_dir_all(object_path(&id).parent().unwrap()).unwrap();
+            fs::write(object_path(&id), &stored).unwrap();
+
+            assert_eq!(decompress(stored).unwrap(), bytes);
+            assert_eq!(FsStore.read(&id).unwrap(), bytes);
+            let mut streamed = vec![];
+            FsStore
+                .open(&id)
+                .unwrap()
+                .read_to_end(&mut streamed)
+                .unwrap();
+            assert_eq!(streamed, bytes);
+            let mut out = vec![];
+            copy_file(&FsStore, &id, &mut out).unwrap();
+            assert_eq!(out, payload);
+        }
+    }
+
+    #[test]
+    fn compression_config_is_validated() {
+        let _repo = TempRepo::new();
+        let cases = [
+            ("compression", "none", true),
+            ("compression", "zlib", true),
+            ("compression", "gzip", false),
+            ("compression", "", false),
+            ("compression_level", "0", true),
+            ("compression_level", "9", true),
+            ("compression_level", "10", false),
+            ("compression_level", "-1", false),
+            ("compression_level", "fast", false),
+            ("compressionlevel", "6", false),
+        ];
+        for (key, value, valid) in cases {
+            let before = get_repo_data(key).unwrap();
+            let res = set_config(key, value);
+            assert_eq!(res.is_ok(), valid, "{} {}", key, value);
+            let expected = if valid {
+                Some(value.to_string())
+            } else {
+                before
+            };
+            assert_eq!(get_repo_data(key).unwrap(), expected, "{} {}", key, value);
+        }
+    }
+
+    #[test]
+    fn flat_objects_are_moved_to_fanout() {
+        let _repo = TempRepo::new();
+        fs::remove_file("./.yeet/repo_data/object_layout").unwrap();
+        let mut ids = vec![];
+        for payload in [b"raw".as_slice(), b"compressed"] {
+            let bytes = object::encode(ObjType::Blob, payload);
+            let id = ObjectId::hash(&bytes);
+            let stored = if payload == b"raw" {
+                bytes
+            } else {
+                compress(&bytes).unwrap()
+            };
+            fs::write(PathBuf::from(OBJECTS_DIR).join(id.as_str()), stored).unwrap();
+            ids.push((id, payload));
+        }
+        // files that are not objects stay where they are
+        fs::write(PathBuf::from(OBJECTS_DIR).join("notes"), "x").unwrap();
+
+        open_repo().unwrap();
+        assert_eq!(
+            get_repo_data("object_layout").unwrap().unwrap(),
+            OBJECT_LAYOUT
+        );
+        for (id, payload) in &ids {
+            assert!(object_path(id).is_file());
+            assert!(!PathBuf::from(OBJECTS_DIR).join(id.as_str()).exists());
+            assert_eq!(get_data(&FsStore, id).unwrap().file_data, *payload);
+        }
+        assert!(PathBuf::from(OBJECTS_DIR).join("notes").is_file());
+
+        // running it again finds nothing to move
+        assert_eq!(migrate_flat_objects().unwrap(), 0);
+        for (id, _) in &ids {
+            assert!(object_path(id).is_file());
+        }
+    }
+
+    #[test]
+    fn atomic_writes_leave_no_temp_files() {
+        let dir = TempDir::new();
+        let path = dir.path.join("file");
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        let names = fs::read_dir(&dir.path)
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect::<Vec<OsString>>();
+        assert_eq!(names, ["file"]);
+
+        // an object that already exists is not written again
+        let _repo = TempRepo::new();
+        let id = write_obj_hash(&FsStore, b"contents", ObjType::Blob).unwrap();
+        let path = object_path(&id);
+        fs::write(&path, "left alone").unwrap();
+        assert_eq!(
+            write_obj_hash(&FsStore, b"contents", ObjType::Blob).unwrap(),
+            id
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"left alone");
+        let names = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect::<Vec<OsString>>();
+        assert_eq!(names, [path.file_name().unwrap()]);
+    }
+
+    #[test]
+    fn types_never_share_ids() {
+        let store = MemoryStore::new();
+        assert_ne!(
+            write_obj_hash(&store, b"same payload", ObjType::Blob).unwrap(),
+            write_obj_hash(&store, b"same payload", ObjType::Tree).unwrap()
+        );
+    }
+
+    #[test]
+    fn u64_repos_are_converted() {
+        let _repo = TempRepo::new();
+        fs::remove_file("./.yeet/repo_data/hash_algorithm").unwrap();
+        let old_objects: [(&str, &[u8]); 4] = [
+            ("11", b"blob\0hello \0 world\n"),
+            ("22", b"tree\0blob 11 a b.txt\ntree 33 dir\n"),
+            ("33", b"tree\0blob 11 same\n"),
+            (
+                "44",
+                b"commit\0tree 22\nparent initial\nauthor test\ntime 5\nfirst\n\nlines",
+            ),
+        ];
+        for (id, bytes) in old_objects {
+            fs::write(PathBuf::from(OBJECTS_DIR).join(id), bytes).unwrap();
+        }
+        fs::write("./.yeet/refs/HEAD", "44").unwrap();
+        fs::write("./.yeet/refs/tags/v1", "44\n").unwrap();
+        open_repo().unwrap();
+
+        // the same objects written by this build
+        let expected = MemoryStore::new();
+        let blob = write_object(&expected, &Object::Blob(b"hello \0 world\n".to_vec())).unwrap();
+        let entry = |name: &str, mode, file_type, hash: &ObjectId| FileData {
+            file_name: OsString::from(name),
+            mode,
+            file_type,
+            hash: hash.clone(),
+        };
+        let dir = Object::Tree(vec![entry("same", FileMode::Regular, ObjType::Blob, &blob)]);
+        let dir = write_object(&expected, &dir).unwrap();
+        let root = Object::Tree(vec![
+            entry("a b.txt", FileMode::Regular, ObjType::Blob, &blob),
+            entry("dir", FileMode::Directory, ObjType::Tree, &dir),
+        ]);
+        let commit = Object::Commit(CommitData {
+            tree: write_object(&expected, &root).unwrap(),
+            parent: None,
+            author: "test".to_string(),
+            time: "5".to_string(),
+            message: "first\n\nlines".to_string(),
+        });
+        let commit = write_object(&expected, &commit).unwrap();
+
+        for id in expected.iter().unwrap() {
+            assert_eq!(FsStore.read(&id).unwrap(), expected.read(&id).unwrap());
+        }
+        assert_eq!(
+            fs::read_to_string("./.yeet/refs/HEAD").unwrap(),
+            commit.as_str()
+        );
+        assert_eq!(
+            fs::read_to_string("./.yeet/refs/tags/v1").unwrap(),
+            commit.as_str()
+        );
+        assert_eq!(
+            get_repo_data("hash_algorithm").unwrap().unwrap(),
+            HASH_ALGORITHM
+        );
+        for (id, _) in old_objects {
+            assert!(!PathBuf::from(OBJECTS_DIR).join(id).exists());
+        }
+        // converted repos are left alone from then on
+        open_repo().unwrap();
+        assert_eq!(
+            fs::read_to_string("./.yeet/refs/HEAD").unwrap(),
+            commit.as_str()
+        );
+    }
+}
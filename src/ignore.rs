@@ -0,0 +1,366 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Error as IOError;
+use std::io::ErrorKind as IOErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::object;
+
+// name of the per directory ignore file
+pub const IGNORE_FILE: &str = ".yeetignore";
+
+// a single line of an ignore file, matched the same way as in a .gitignore
+#[derive(Clone)]
+pub struct Pattern {
+    // glob with the leading "!", leading "/" and trailing "/" removed
+    glob: Vec<u8>,
+    // directory of the ignore file relative to the repo root, empty for the root
+    base: Vec<u8>,
+    pub negated: bool,
+    pub dir_only: bool,
+    // patterns with a "/" before the end match the whole path below base instead of the name
+    anchored: bool,
+    // where the pattern came from
+    pub source: PathBuf,
+    pub line: usize,
+    pub text: String,
+}
+
+impl Pattern {
+    fn parse(line: &[u8], base: &[u8], source: &Path, line_no: usize) -> Option<Pattern> {
+        let text = String::from_utf8_lossy(line).into_owned();
+        let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || line[0] == b'#' {
+            return None;
+        }
+        // trailing spaces are dropped unless escaped with a backslash
+        while line.ends_with(b" ") && !line.ends_with(b"\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        let negated = line.starts_with(b"!");
+        if negated {
+            line = &line[1..];
+        }
+        // "\!" and "\#" match names starting with those characters
+        if line.starts_with(b"\\!") || line.starts_with(b"\\#") {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with(b"/");
+        while line.ends_with(b"/") {
+            line = &line[..line.len() - 1];
+        }
+        let anchored = line.contains(&b'/');
+        let line = line.strip_prefix(b"/").unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        return Some(Pattern {
+            glob: line.to_vec(),
+            base: base.to_vec(),
+            negated,
+            dir_only,
+            anchored,
+            source: source.to_path_buf(),
+            line: line_no,
+            text,
+        });
+    }
+
+    // path is relative to the repo root with "/" between components
+    fn matches(&self, path: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let path = if self.base.is_empty() {
+            path
+        } else {
+            match path
+                .strip_prefix(self.base.as_slice())
+                .and_then(|x| x.strip_prefix(b"/"))
+            {
+                Some(x) => x,
+                None => return false,
+            }
+        };
+        if self.anchored {
+            return glob_match(&self.glob, path, true);
+        }
+        let name = match path.iter().rposition(|x| *x == b'/') {
+            Some(i) => &path[i + 1..],
+            None => path,
+        };
+        return glob_match(&self.glob, name, true);
+    }
+}
+
+// patterns of every ignore file from the repo root down to one directory, later patterns
+// take precedence like in git
+#[derive(Default, Clone)]
+pub struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+impl Ignore {
+    pub fn new() -> Ignore {
+        Ignore::default()
+    }
+
+    // adds the patterns of the ignore file in dir, dir_rel is dir relative to the repo root
+    // and empty for the root itself
+    pub fn load(&mut self, dir: &Path, dir_rel: &[u8]) -> Result<(), IOError> {
+        let source = dir.join(IGNORE_FILE);
+        let bytes = match fs::read(&source) {
+            Ok(x) => x,
+            Err(e) if e.kind() == IOErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for (i, line) in bytes.split(|x| *x == b'\n').enumerate() {
+            if let Some(p) = Pattern::parse(line, dir_rel, &source, i + 1) {
+                self.patterns.push(p);
+            }
+        }
+        Ok(())
+    }
+
+    // the patterns loaded so far, used to go back after leaving a directory
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.patterns.truncate(len);
+    }
+
+    // last pattern matching path, the path is ignored if that pattern is not negated
+    pub fn matched(&self, path: &[u8], is_dir: bool) -> Option<&Pattern> {
+        return self.patterns.iter().rev().find(|x| x.matches(path, is_dir));
+    }
+
+    pub fn is_ignored(&self, path: &[u8], is_dir: bool) -> bool {
+        return self.matched(path, is_dir).is_some_and(|x| !x.negated);
+    }
+}
+
+// patterns of the ignore files in root and every directory above rel, not including the one
+// in rel itself
+pub fn load_parents(root: &Path, rel: &[u8]) -> Result<Ignore, IOError> {
+    let mut ignore = Ignore::new();
+    if rel.is_empty() {
+        return Ok(ignore);
+    }
+    ignore.load(root, b"")?;
+    for (i, x) in rel.iter().enumerate() {
+        if *x == b'/' {
+            let dir = &rel[..i];
+            ignore.load(&root.join(object::name_from_bytes(dir)), dir)?;
+        }
+    }
+    return Ok(ignore);
+}
+
+// finds the pattern deciding whether write_tree would ignore path, which is relative to root
+// with "/" between components. walks down from root loading ignore files and stops at the
+// first ignored directory since write_tree never looks inside those.
+// None if no pattern matches, a negated pattern means the path is not ignored
+pub fn check(root: &Path, path: &[u8], is_dir: bool) -> Result<Option<Pattern>, IOError> {
+    let mut ignore = Ignore::new();
+    ignore.load(root, b"")?;
+    let names = path.split(|x| *x == b'/').collect::<Vec<&[u8]>>();
+    let mut rel: Vec<u8> = vec![];
+    for (i, name) in names.iter().enumerate() {
+        rel = join(&rel, &object::name_from_bytes(name));
+        // write_tree skips every .yeet directory whatever the ignore files say
+        if *name == b".yeet" {
+            return Ok(Pattern::parse(b".yeet", b"", Path::new(""), 0));
+        }
+        let last = i == names.len() - 1;
+        let matched = ignore.matched(&rel, if last { is_dir } else { true });
+        if last || matched.is_some_and(|x| !x.negated) {
+            return Ok(matched.cloned());
+        }
+        ignore.load(&root.join(object::name_from_bytes(&rel)), &rel)?;
+    }
+    return Ok(None);
+}
+
+// shell style glob on bytes where "*", "?" and "[...]" never match "/" and a "**" component
+// matches any number of directories
+fn glob_match(p: &[u8], s: &[u8], segment_start: bool) -> bool {
+    if segment_start && p.starts_with(b"**") && (p.len() == 2 || p[2] == b'/') {
+        // a trailing "**" matches everything below
+        if p.len() == 2 {
+            return true;
+        }
+        let rest = &p[3..];
+        if glob_match(rest, s, true) {
+            return true;
+        }
+        return s
+            .iter()
+            .enumerate()
+            .any(|(i, x)| *x == b'/' && glob_match(rest, &s[i + 1..], true));
+    }
+    match p.first() {
+        None => s.is_empty(),
+        Some(b'*') => {
+            let rest = &p[p.iter().take_while(|x| **x == b'*').count()..];
+            for i in 0..=s.len() {
+                if glob_match(rest, &s[i..], false) {
+                    return true;
+                }
+                if i < s.len() && s[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => !s.is_empty() && s[0] != b'/' && glob_match(&p[1..], &s[1..], false),
+        Some(b'[') => match match_class(&p[1..], s.first()) {
+            Some((true, len)) => glob_match(&p[1 + len..], &s[1..], false),
+            Some((false, _)) => false,
+            // no closing "]" so the "[" is literal
+            None => s.first() == Some(&b'[') && glob_match(&p[1..], &s[1..], false),
+        },
+        Some(b'\\') if p.len() > 1 => {
+            s.first() == Some(&p[1]) && glob_match(&p[2..], &s[1..], false)
+        }
+        Some(c) => s.first() == Some(c) && glob_match(&p[1..], &s[1..], *c == b'/'),
+    }
+}
+
+// matches c against the class starting after "[", returns whether it matched and the length
+// of the class including the closing "]", None if the class is never closed
+fn match_class(p: &[u8], c: Option<&u8>) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(p.first(), Some(b'!' | b'^'));
+    if negated {
+        i += 1;
+    }
+    let mut found = false;
+    let mut first = true;
+    loop {
+        let x = *p.get(i)?;
+        // a "]" right after the "[" is part of the class
+        if x == b']' && !first {
+            break;
+        }
+        first = false;
+        if p.get(i + 1) == Some(&b'-') && p.get(i + 2).is_some_and(|y| *y != b']') {
+            let end = p[i + 2];
+            if c.is_some_and(|c| x <= *c && *c <= end) {
+                found = true;
+            }
+            i += 3;
+        } else {
+            if c == Some(&x) {
+                found = true;
+            }
+            i += 1;
+        }
+    }
+    let matched = match c {
+        Some(b'/') | None => false,
+        Some(_) => found != negated,
+    };
+    return Some((matched, i + 1));
+}
+
+// appends name to a path relative to the repo root
+pub fn join(rel: &[u8], name: &OsStr) -> Vec<u8> {
+    let mut path = rel.to_vec();
+    if !path.is_empty() {
+        path.push(b'/');
+    }
+    path.extend_from_slice(&object::name_to_bytes(name));
+    return path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignore(lines: &str, base: &[u8]) -> Ignore {
+        let patterns = lines
+            .split('\n')
+            .enumerate()
+            .filter_map(|(i, x)| Pattern::parse(x.as_bytes(), base, Path::new(IGNORE_FILE), i + 1))
+            .collect();
+        return Ignore { patterns };
+    }
+
+    #[test]
+    fn patterns() {
+        // patterns, base of the ignore file, path, is_dir, ignored
+        let cases: &[(&str, &str, &str, bool, bool)] = &[
+            ("*.o", "", "a.o", false, true),
+            ("*.o", "", "src/deep/a.o", false, true),
+            ("*.o", "", "a.oo", false, false),
+            ("# comment", "", "# comment", false, false),
+            ("\\#name", "", "#name", false, true),
+            // "**"
+            ("**/build", "", "build", true, true),
+            ("**/build", "", "a/b/build", true, true),
+            ("docs/**", "", "docs/a/b.md", false, true),
+            ("docs/**", "", "docs", true, false),
+            ("a/**/z", "", "a/z", false, true),
+            ("a/**/z", "", "a/b/c/z", false, true),
+            ("a/**/z", "", "b/a/z", false, false),
+            ("a/*/z", "", "a/b/c/z", false, false),
+            // leading "/"
+            ("/root.txt", "", "root.txt", false, true),
+            ("/root.txt", "", "sub/root.txt", false, false),
+            ("/x", "sub", "sub/x", false, true),
+            ("/x", "sub", "x", false, false),
+            ("x", "sub", "sub/deep/x", false, true),
+            // trailing "/"
+            ("out/", "", "out", true, true),
+            ("out/", "", "out", false, false),
+            ("out/", "", "a/out", true, true),
+            // "!"
+            ("*.log\n!keep.log", "", "a.log", false, true),
+            ("*.log\n!keep.log", "", "keep.log", false, false),
+            ("!keep.log\n*.log", "", "keep.log", false, true),
+            ("\\!bang", "", "!bang", false, true),
+            // character classes
+            ("file[0-9]", "", "file7", false, true),
+            ("file[0-9]", "", "filex", false, false),
+            ("file[!0-9]", "", "filex", false, true),
+            ("file[^0-9]", "", "file7", false, false),
+            ("[]a]", "", "]", false, true),
+            ("a[/]b", "", "a/b", false, false),
+            ("[abc", "", "[abc", false, true),
+            ("?.txt", "", "a.txt", false, true),
+            ("?.txt", "", "ab.txt", false, false),
+            // trailing spaces
+            ("space ", "", "space", false, true),
+            ("space\\ ", "", "space ", false, true),
+        ];
+        for (lines, base, path, is_dir, ignored) in cases {
+            let ignore = ignore(lines, base.as_bytes());
+            assert_eq!(
+                ignore.is_ignored(path.as_bytes(), *is_dir),
+                *ignored,
+                "{:?} in {:?} against {:?}",
+                lines,
+                base,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn yeet_dir_is_always_ignored() {
+        let dir = crate::testutil::TempDir::new();
+        fs::write(dir.path.join(IGNORE_FILE), "!.yeet\n!*\n").unwrap();
+        for path in [".yeet", ".yeet/index", "sub/.yeet/objects"] {
+            let matched = check(&dir.path, path.as_bytes(), false).unwrap();
+            assert!(matched.is_some_and(|x| !x.negated), "{}", path);
+        }
+        let matched = check(&dir.path, b"yeet/.yeetignore", false).unwrap();
+        assert!(matched.is_some_and(|x| x.negated));
+    }
+}
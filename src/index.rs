@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Error as IOError;
+use std::io::ErrorKind as IOErrorKind;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data::{self, ObjectId};
+use crate::object::{self, FileData, FileMode, ObjType};
+use crate::store::ObjectStore;
+
+// index layout (all integers are big endian):
+//   "YNDX" version:u32 count:u32 then count entries of
+//   mode:u32 type:u8 id:32 bytes mtime_secs:i64 mtime_nanos:u32 size:u64 inode:u64
+//   path_len:u32 path:path_len bytes
+// type is 0 for blobs, 1 for chunks and 2 for trees, which only the stat cache holds.
+// entries are sorted by path, paths are relative to the repo root with "/" between components
+pub const INDEX_PATH: &str = "./.yeet/index";
+const INDEX_MAGIC: &[u8; 4] = b"YNDX";
+const INDEX_VERSION: u32 = 1;
+const ENTRY_LEN: usize = 4 + 1 + 32 + 8 + 4 + 8 + 8 + 4;
+
+// what the file looked like when it was added, all zero for entries that were never
+// compared against the working tree
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Stat {
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+    pub size: u64,
+    pub inode: u64,
+}
+
+impl Stat {
+    // metadata has to come from symlink_metadata so symlinks are not followed
+    pub fn from_metadata(metadata: &fs::Metadata) -> Stat {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Stat {
+            mtime_secs: mtime.as_secs() as i64,
+            mtime_nanos: mtime.subsec_nanos(),
+            size: metadata.len(),
+            inode: inode(metadata),
+        }
+    }
+
+    // a file written again in the same second it was hashed can keep its mtime and size on
+    // filesystems with coarse timestamps, so stats that recent are not kept. now is the time
+    // the command started in seconds since the epoch
+    pub fn unless_racy(self, now: i64) -> Stat {
+        if self.mtime_secs >= now {
+            return Stat::default();
+        }
+        return self;
+    }
+
+    // whether a file with stat other can be assumed to have the same content, never true for
+    // an empty stat
+    pub fn matches(&self, other: &Stat) -> bool {
+        return *self != Stat::default() && self == other;
+    }
+}
+
+pub fn now_secs() -> i64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0);
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    return metadata.ino();
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    return 0;
+}
+
+#[derive(Clone)]
+pub struct IndexEntry {
+    pub path: Vec<u8>,
+    pub mode: FileMode,
+    pub type_: ObjType,
+    pub id: ObjectId,
+    pub stat: Stat,
+}
+
+pub struct Index {
+    entries: BTreeMap<Vec<u8>, IndexEntry>,
+    // when the index was loaded, see Stat::unless_racy
+    now: i64,
+}
+
+fn bad_index() -> IOError {
+    IOError::new(IOErrorKind::InvalidData, "Invalid index file")
+}
+
+// entries of an index or stat cache file by path
+pub fn decode_entries(
+    bytes: &[u8],
+    magic: &[u8; 4],
+) -> Result<BTreeMap<Vec<u8>, IndexEntry>, IOError> {
+    if bytes.len() < 12 || &bytes[..4] != magic {
+        return Err(bad_index());
+    }
+    if u32::from_be_bytes(bytes[4..8].try_into().unwrap()) != INDEX_VERSION {
+        return Err(bad_index());
+    }
+    let count = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+    let mut entries = BTreeMap::new();
+    let mut pos = 12;
+    for _ in 0..count {
+        let x = bytes.get(pos..pos + ENTRY_LEN).ok_or_else(bad_index)?;
+        let mode = u32::from_be_bytes(x[0..4].try_into().unwrap());
+        let mode = FileMode::parse(&format!("{:06o}", mode)).ok_or_else(bad_index)?;
+        let type_ = match x[4] {
+            0 => ObjType::Blob,
+            1 => ObjType::Chunks,
+            2 => ObjType::Tree,
+            _ => return Err(bad_index()),
+        };
+        let id = ObjectId::from_bytes(x[5..37].try_into().unwrap());
+        let stat = Stat {
+            mtime_secs: i64::from_be_bytes(x[37..45].try_into().unwrap()),
+            mtime_nanos: u32::from_be_bytes(x[45..49].try_into().unwrap()),
+            size: u64::from_be_bytes(x[49..57].try_into().unwrap()),
+            inode: u64::from_be_bytes(x[57..65].try_into().unwrap()),
+        };
+        let path_len = u32::from_be_bytes(x[65..69].try_into().unwrap()) as usize;
+        pos += ENTRY_LEN;
+        let path = bytes
+            .get(pos..pos + path_len)
+            .ok_or_else(bad_index)?
+            .to_vec();
+        pos += path_len;
+        entries.insert(
+            path.clone(),
+            IndexEntry {
+                path,
+                mode,
+                type_,
+                id,
+                stat,
+            },
+        );
+    }
+    if pos != bytes.len() {
+        return Err(bad_index());
+    }
+    return Ok(entries);
+}
+
+pub fn encode_entries<'a>(
+    magic: &[u8; 4],
+    entries: impl ExactSizeIterator<Item = &'a IndexEntry>,
+) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![];
+    bytes.extend_from_slice(magic);
+    bytes.extend_from_slice(&INDEX_VERSION.to_be_bytes());
+    bytes.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for x in entries {
+        let mode = u32::from_str_radix(x.mode.as_str(), 8).unwrap();
+        bytes.extend_from_slice(&mode.to_be_bytes());
+        bytes.push(match x.type_ {
+            ObjType::Chunks => 1,
+            ObjType::Tree => 2,
+            _ => 0,
+        });
+        bytes.extend_from_slice(&x.id.to_bytes());
+        bytes.extend_from_slice(&x.stat.mtime_secs.to_be_bytes());
+        bytes.extend_from_slice(&x.stat.mtime_nanos.to_be_bytes());
+        bytes.extend_from_slice(&x.stat.size.to_be_bytes());
+        bytes.extend_from_slice(&x.stat.inode.to_be_bytes());
+        bytes.extend_from_slice(&(x.path.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&x.path);
+    }
+    return bytes;
+}
+
+impl Index {
+    // reads the index, a repo without one starts from the tree of HEAD
+    pub fn load(store: &dyn ObjectStore) -> Result<Index, IOError> {
+        let bytes = match fs::read(INDEX_PATH) {
+            Ok(x) => x,
+            Err(e) if e.kind() == IOErrorKind::NotFound => return Index::from_head(store),
+            Err(e) => return Err(e),
+        };
+        let entries = decode_entries(&bytes, INDEX_MAGIC)?;
+        return Ok(Index {
+            entries,
+            now: now_secs(),
+        });
+    }
+
+    // an index holding every file of HEAD's tree with empty stats
+    pub fn from_head(store: &dyn ObjectStore) -> Result<Index, IOError> {
+        let head = data::get_ref(&"HEAD".to_string(), PathBuf::new())?;
+        // HEAD holds "initial" until the first commit
+        match ObjectId::parse(&head) {
+            Some(commit) => {
+                let tree = data::read_commit_data(store, &commit)?.tree;
+                return Index::from_tree(store, &tree);
+            }
+            None => {
+                return Ok(Index {
+                    entries: BTreeMap::new(),
+                    now: now_secs(),
+                })
+            }
+        }
+    }
+
+    // an index holding every file of tree with empty stats
+    pub fn from_tree(store: &dyn ObjectStore, tree: &ObjectId) -> Result<Index, IOError> {
+        let mut index = Index {
+            entries: BTreeMap::new(),
+            now: now_secs(),
+        };
+        index.read_tree(store, tree, &[])?;
+        return Ok(index);
+    }
+
+    // adds every file of tree below the directory prefix, with an empty stat
+    fn read_tree(
+        &mut self,
+        store: &dyn ObjectStore,
+        tree: &ObjectId,
+        prefix: &[u8],
+    ) -> Result<(), IOError> {
+        for x in data::decode_dir_data(store, tree)? {
+            let mut path = prefix.to_vec();
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.extend_from_slice(&object::name_to_bytes(&x.file_name));
+            if x.file_type == ObjType::Tree {
+                self.read_tree(store, &x.hash, &path)?;
+            } else {
+                self.add(IndexEntry {
+                    path,
+                    mode: x.mode,
+                    type_: x.file_type,
+                    id: x.hash,
+                    stat: Stat::default(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), IOError> {
+        let bytes = encode_entries(INDEX_MAGIC, self.entries.values());
+        return data::write_atomic(&PathBuf::from(INDEX_PATH), &bytes);
+    }
+
+    pub fn get(&self, path: &[u8]) -> Option<&IndexEntry> {
+        return self.entries.get(path);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &IndexEntry> {
+        return self.entries.values();
+    }
+
+    // paths of all entries at or below path
+    pub fn paths_under(&self, path: &[u8]) -> Vec<Vec<u8>> {
+        return self
+            .entries
+            .range(path.to_vec()..)
+            .map(|x| x.0)
+            .take_while(|x| x.starts_with(path))
+            .filter(|x| path.is_empty() || x.len() == path.len() || x[path.len()] == b'/')
+            .cloned()
+            .collect();
+    }
+
+    // a file replaces any directory of the same name and the other way around
+    pub fn add(&mut self, mut entry: IndexEntry) {
+        entry.stat = entry.stat.unless_racy(self.now);
+        for i in self.paths_under(&entry.path) {
+            self.entries.remove(&i);
+        }
+        for (i, x) in entry.path.iter().enumerate() {
+            if *x == b'/' {
+                self.entries.remove(&entry.path[..i]);
+            }
+        }
+        self.entries.insert(entry.path.clone(), entry);
+    }
+
+    pub fn remove(&mut self, path: &[u8]) -> bool {
+        return self.entries.remove(path).is_some();
+    }
+
+    // writes the trees for all entries and returns the id of the root tree
+    pub fn write_tree(&self, store: &dyn ObjectStore) -> Result<ObjectId, IOError> {
+        let entries = self.entries.values().collect::<Vec<&IndexEntry>>();
+        return write_subtree(store, &entries, 0);
+    }
+}
+
+// entries all start with the same directory, which is prefix bytes long including its
+// trailing "/". entries of a subdirectory are next to each other since they are sorted
+fn write_subtree(
+    store: &dyn ObjectStore,
+    entries: &[&IndexEntry],
+    prefix: usize,
+) -> Result<ObjectId, IOError> {
+    let mut files: Vec<FileData> = vec![];
+    let mut i = 0;
+    while i < entries.len() {
+        let rest = &entries[i].path[prefix..];
+        match rest.iter().position(|x| *x == b'/') {
+            None => {
+                files.push(FileData {
+                    file_name: object::name_from_bytes(rest),
+                    mode: entries[i].mode,
+                    file_type: entries[i].type_,
+                    hash: entries[i].id.clone(),
+                });
+                i += 1;
+            }
+            Some(slash) => {
+                let dir = &rest[..=slash];
+                let end = i + entries[i..]
+                    .iter()
+                    .take_while(|x| x.path[prefix..].starts_with(dir))
+                    .count();
+                let hash = write_subtree(store, &entries[i..end], prefix + dir.len())?;
+                files.push(FileData {
+                    file_name: object::name_from_bytes(&rest[..slash]),
+                    mode: FileMode::Directory,
+                    file_type: ObjType::Tree,
+                    hash,
+                });
+                i = end;
+            }
+        }
+    }
+    return data::hash_dir(store, &files);
+}
@@ -0,0 +1,15 @@
+#![allow(clippy::needless_return)]
+pub mod cache;
+pub mod chunk;
+pub mod cli;
+pub mod data;
+pub mod ignore;
+pub mod index;
+pub mod merge;
+pub mod object;
+pub mod pack;
+pub mod store;
+pub mod yeet;
+
+#[cfg(test)]
+mod testutil;
@@ -1,14 +1,19 @@
-#![feature(fs_try_exists)]
-use std::{env, path::PathBuf};
+#![allow(clippy::needless_return)]
+use std::{env, path::PathBuf, process::exit};
 
-pub mod cli;
-pub mod data;
-pub mod yeet;
+use ::yeet::{cli, data, store::FsStore, yeet};
 
 // TODO: clean up 3-depth match (monkaW)
 fn main() {
     let args = env::args().collect::<Vec<String>>();
     if let Some(config) = cli::parse_args(&args) {
+        let store = FsStore;
+        if !matches!(config.command, cli::Options::Init) {
+            if let Err(e) = data::open_repo() {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
         match config.command {
             cli::Options::Init => {
                 yeet::init_repo();
@@ -17,7 +22,7 @@ fn main() {
                 let file_path = config.args;
                 match file_path {
                     Some(path) => {
-                        yeet::cat_file(&path[0]);
+                        yeet::cat_file(&store, &path[0]);
                     }
                     None => {
                         unreachable!();
@@ -28,47 +33,79 @@ fn main() {
                 let file_path = config.args;
                 match file_path {
                     Some(path) => {
-                        yeet::hash_file(PathBuf::from(&path[0]), true).unwrap();
+                        yeet::hash_file(&store, PathBuf::from(&path[0]), true).unwrap();
                     }
                     None => {
                         unreachable!();
                     }
                 }
             }
-            cli::Options::WriteTree => {
-                let rev_id = yeet::write_tree(PathBuf::from(".")).expect("Error writing tree");
-                println!("New revision id: {}", rev_id);
+            cli::Options::WriteTree { jobs } => {
+                match yeet::write_tree(&store, PathBuf::from("."), jobs) {
+                    Ok(rev_id) => println!("New revision id: {}", rev_id),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        exit(1);
+                    }
+                }
             }
             cli::Options::ReadTree => {
                 let hash = config.args.unwrap()[0].clone();
-                yeet::read_tree(hash, PathBuf::from("./restored"));
+                yeet::read_tree(&store, hash, yeet::CheckoutMode::Safe);
             }
             cli::Options::SetAuthor => {
                 let name = config.args.unwrap()[0].clone();
                 yeet::set_author(name);
             }
-            cli::Options::Commit => {
+            cli::Options::Config => {
+                let args = config.args.unwrap();
+                let key = args[0].clone();
+                let value = args.get(1).cloned();
+                yeet::config(key, value);
+            }
+            cli::Options::Commit { abbrev } => {
                 let message = config.args.unwrap()[0].clone();
-                if let Err(e) = yeet::commit(message) {
+                if let Err(e) = yeet::commit(&store, message, abbrev) {
                     println!("Error: {}", e);
                 }
             }
-            cli::Options::Log => {
+            cli::Options::Log { abbrev } => {
                 let hash = config.args.unwrap()[0].clone();
-                yeet::log(hash);
+                yeet::log(&store, hash, abbrev);
             }
-            cli::Options::Checkout => {
-                let hash = config.args.unwrap()[0].clone();
-                yeet::checkout(hash);
+            cli::Options::Checkout { mode } => {
+                let commit_id = config.args.unwrap()[0].clone();
+                yeet::checkout(&store, commit_id, mode);
             }
             cli::Options::Tag => {
                 let args = config.args.unwrap();
                 let tag = args[0].clone();
                 let hash = args[1].clone();
-                yeet::tag_commit(tag, hash);
+                yeet::tag_commit(&store, tag, hash);
+            }
+            cli::Options::Pack => {
+                yeet::pack(&store);
+            }
+            cli::Options::Fsck => {
+                yeet::fsck(&store);
+            }
+            cli::Options::Gc { prune, dry_run } => {
+                yeet::gc(&store, prune, dry_run);
+            }
+            cli::Options::CheckIgnore { verbose } => {
+                yeet::check_ignore(&config.args.unwrap(), verbose);
+            }
+            cli::Options::Add { jobs } => {
+                yeet::add(&store, &config.args.unwrap(), jobs);
+            }
+            cli::Options::Rm { cached } => {
+                yeet::rm(&store, &config.args.unwrap(), cached);
+            }
+            cli::Options::Restore { source, paths } => {
+                yeet::restore(&store, source, &paths);
             }
-            cli::Options::K => {
-                yeet::k();
+            cli::Options::K { abbrev } => {
+                yeet::k(&store, abbrev);
             }
         }
     }
@@ -0,0 +1,162 @@
+// line based three way merge, checkout --carry uses it to keep local changes to files the
+// checkout changes too
+
+// largest diff worked out, in lines of the base times lines of the other side once the lines
+// both start and end with are taken off. bigger diffs are treated as not mergeable
+const MAX_DIFF_CELLS: usize = 4 * 1024 * 1024;
+
+// base[start..end] is replaced by lines
+#[derive(Debug, PartialEq)]
+struct Hunk<'a> {
+    start: usize,
+    end: usize,
+    lines: Vec<&'a [u8]>,
+}
+
+// lines with their line endings, so joining them gives back data
+fn lines(data: &[u8]) -> Vec<&[u8]> {
+    return data.split_inclusive(|x| *x == b'\n').collect();
+}
+
+// changes turning base into other, None if the diff is too big to work out
+fn diff<'a>(base: &[&[u8]], other: &[&'a [u8]]) -> Option<Vec<Hunk<'a>>> {
+    let prefix = base.iter().zip(other).take_while(|(a, b)| a == b).count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(other[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &base[prefix..base.len() - suffix];
+    let b = &other[prefix..other.len() - suffix];
+    if a.len().saturating_mul(b.len()) > MAX_DIFF_CELLS {
+        return None;
+    }
+
+    // lcs[i * width + j] is the length of the longest common subsequence of a[i..] and b[j..]
+    let width = b.len() + 1;
+    let mut lcs = vec![0u32; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i * width + j] = if a[i] == b[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut hunks = vec![];
+    let mut hunk: Option<Hunk> = None;
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            hunks.extend(hunk.take());
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let h = hunk.get_or_insert(Hunk {
+            start: prefix + i,
+            end: prefix + i,
+            lines: vec![],
+        });
+        if j < b.len() && (i == a.len() || lcs[i * width + j + 1] >= lcs[(i + 1) * width + j]) {
+            h.lines.push(b[j]);
+            j += 1;
+        } else {
+            i += 1;
+            h.end = prefix + i;
+        }
+    }
+    hunks.extend(hunk);
+    return Some(hunks);
+}
+
+// applies both the changes from base to ours and from base to theirs. None when they touch
+// the same or neighbouring lines, unless both sides made the same change
+pub fn merge(base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<Vec<u8>> {
+    let base = lines(base);
+    let (ours, theirs) = (lines(ours), lines(theirs));
+    let mut hunks = diff(&base, &ours)?;
+    hunks.append(&mut diff(&base, &theirs)?);
+    hunks.sort_by_key(|x| (x.start, x.end));
+    hunks.dedup();
+
+    let mut merged: Vec<&[u8]> = vec![];
+    let mut pos = 0;
+    let mut last_end: Option<usize> = None;
+    for h in &hunks {
+        // hunks of one side never touch, so this only finds changes from both
+        if last_end.is_some_and(|x| h.start <= x) {
+            return None;
+        }
+        merged.extend_from_slice(&base[pos..h.start]);
+        merged.extend_from_slice(&h.lines);
+        pos = h.end;
+        last_end = Some(h.end);
+    }
+    merged.extend_from_slice(&base[pos..]);
+    return Some(merged.concat());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges() {
+        let base = "1\n2\n3\n4\n5\n6\n7\n";
+        // ours, theirs, merged
+        let cases: &[(&str, &str, Option<&str>)] = &[
+            (base, base, Some(base)),
+            ("1\nX\n3\n4\n5\n6\n7\n", base, Some("1\nX\n3\n4\n5\n6\n7\n")),
+            (base, "1\n2\n3\n4\n5\nY\n7\n", Some("1\n2\n3\n4\n5\nY\n7\n")),
+            (
+                "1\nX\n3\n4\n5\n6\n7\n",
+                "1\n2\n3\n4\n5\nY\n7\n",
+                Some("1\nX\n3\n4\n5\nY\n7\n"),
+            ),
+            (
+                "0\n1\n2\n3\n4\n5\n6\n7\n",
+                "1\n2\n3\n4\n5\n6\n7\n8",
+                Some("0\n1\n2\n3\n4\n5\n6\n7\n8"),
+            ),
+            (
+                "1\n2\n3\n5\n6\n7\n",
+                "1\n2\n3\n4\n5\n6\n",
+                Some("1\n2\n3\n5\n6\n"),
+            ),
+            // the same change on both sides
+            (
+                "1\nX\n3\n4\n5\n6\n7\n",
+                "1\nX\n3\n4\n5\n6\n7\n",
+                Some("1\nX\n3\n4\n5\n6\n7\n"),
+            ),
+            // overlapping or neighbouring changes
+            ("1\nX\n3\n4\n5\n6\n7\n", "1\nY\n3\n4\n5\n6\n7\n", None),
+            ("1\nX\n3\n4\n5\n6\n7\n", "1\n2\nY\n4\n5\n6\n7\n", None),
+            ("1\n2\nA\n3\n4\n5\n6\n7\n", "1\n2\nB\n3\n4\n5\n6\n7\n", None),
+            ("", "1\n2\n3\n4\n5\n6\n7\n8\n", None),
+        ];
+        for (ours, theirs, merged) in cases {
+            let result = merge(base.as_bytes(), ours.as_bytes(), theirs.as_bytes());
+            assert_eq!(
+                result.as_deref(),
+                merged.map(|x| x.as_bytes()),
+                "{:?} {:?}",
+                ours,
+                theirs
+            );
+        }
+    }
+
+    #[test]
+    fn binary_and_missing_newlines() {
+        let base = b"a\0\xff\nb\nc\nd\ne";
+        let ours = b"a\0\xfe\nb\nc\nd\ne";
+        let theirs = b"a\0\xff\nb\nc\nd\nE";
+        assert_eq!(merge(base, ours, theirs).unwrap(), b"a\0\xfe\nb\nc\nd\nE");
+        assert_eq!(merge(b"", b"", b"x").unwrap(), b"x");
+    }
+}
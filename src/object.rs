@@ -0,0 +1,674 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::Error as IOError;
+use std::io::ErrorKind as IOErrorKind;
+use std::io::Read;
+
+use crate::data::ObjectId;
+
+const SEPARATOR: u8 = 0x00u8;
+const MAX_HEADER_LEN: usize = 32;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum ObjType {
+    Blob,
+    Tree,
+    Commit,
+    Tag,
+    // list of blobs that make up a large file, see chunk.rs
+    Chunks,
+}
+
+impl ObjType {
+    // name used in object headers and tree entries
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObjType::Blob => "blob",
+            ObjType::Tree => "tree",
+            ObjType::Commit => "commit",
+            ObjType::Tag => "tag",
+            ObjType::Chunks => "chunks",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<ObjType> {
+        match s {
+            "blob" => Some(ObjType::Blob),
+            "tree" => Some(ObjType::Tree),
+            "commit" => Some(ObjType::Commit),
+            "tag" => Some(ObjType::Tag),
+            "chunks" => Some(ObjType::Chunks),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ObjType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FileMode {
+    Regular,
+    Executable,
+    // the blob holds the link target
+    Symlink,
+    Directory,
+}
+
+impl FileMode {
+    // same numbers as git uses
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileMode::Regular => "100644",
+            FileMode::Executable => "100755",
+            FileMode::Symlink => "120000",
+            FileMode::Directory => "040000",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<FileMode> {
+        match s {
+            "100644" => Some(FileMode::Regular),
+            "100755" => Some(FileMode::Executable),
+            "120000" => Some(FileMode::Symlink),
+            "040000" => Some(FileMode::Directory),
+            _ => None,
+        }
+    }
+
+    // whether a tree entry with this mode may point at an object of type_
+    fn allows(&self, type_: ObjType) -> bool {
+        match self {
+            FileMode::Regular | FileMode::Executable => {
+                matches!(type_, ObjType::Blob | ObjType::Chunks)
+            }
+            FileMode::Symlink => type_ == ObjType::Blob,
+            FileMode::Directory => type_ == ObjType::Tree,
+        }
+    }
+}
+
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// a single entry of a tree object
+pub struct FileData {
+    pub file_name: OsString,
+    pub mode: FileMode,
+    pub file_type: ObjType,
+    pub hash: ObjectId,
+}
+
+pub struct CommitData {
+    pub tree: ObjectId,
+    // None for the first commit in the repo
+    pub parent: Option<ObjectId>,
+    pub author: String,
+    pub time: String,
+    pub message: String,
+}
+
+// annotated tag pointing at another object
+pub struct TagData {
+    pub object: ObjectId,
+    pub type_: ObjType,
+    pub tag: String,
+    pub tagger: String,
+    pub time: String,
+    pub message: String,
+}
+
+pub enum Object {
+    Blob(Vec<u8>),
+    Tree(Vec<FileData>),
+    Commit(CommitData),
+    Tag(TagData),
+    Chunks(Vec<ObjectId>),
+}
+
+fn invalid(message: String) -> IOError {
+    IOError::new(IOErrorKind::InvalidData, message)
+}
+
+// prepends the "<type> <len>" header, the result is what gets hashed and stored
+pub fn encode(type_: ObjType, data: &[u8]) -> Vec<u8> {
+    let header = format!("{} {}", type_, data.len());
+    return [header.as_bytes(), &[SEPARATOR], data].concat();
+}
+
+// splits a stored object into its type and payload
+// only the first SEPARATOR ends the header, everything after it is returned as is
+pub fn decode(id: &ObjectId, mut bytes: Vec<u8>) -> Result<(ObjType, Vec<u8>), IOError> {
+    let header_end = bytes
+        .iter()
+        .position(|&x| x == SEPARATOR)
+        .ok_or_else(|| invalid(format!("Missing header in object {}", id)))?;
+    let data = bytes.split_off(header_end + 1);
+    let (type_, len) = parse_header(id, &bytes[..header_end])?;
+
+    if data.len() != len {
+        return Err(invalid(format!(
+            "Object {} has length {} but its header says {}",
+            id,
+            data.len(),
+            len
+        )));
+    }
+    return Ok((type_, data));
+}
+
+// splits an object header into its type and payload length
+pub fn parse_header(id: &ObjectId, header: &[u8]) -> Result<(ObjType, usize), IOError> {
+    let bad_header = || invalid(format!("Invalid header in object {}", id));
+    let header = std::str::from_utf8(header).map_err(|_| bad_header())?;
+    let (type_name, len) = header.split_once(' ').ok_or_else(bad_header)?;
+    let type_ = ObjType::parse(type_name)
+        .ok_or_else(|| invalid(format!("Unknown type {} in object {}", type_name, id)))?;
+    let len = len.parse::<usize>().map_err(|_| bad_header())?;
+    return Ok((type_, len));
+}
+
+// reads the header from the start of a stored object, leaving reader at the payload
+pub fn read_header(id: &ObjectId, reader: &mut dyn Read) -> Result<(ObjType, usize), IOError> {
+    let mut header: Vec<u8> = vec![];
+    let mut byte = [0u8];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Err(invalid(format!("Missing header in object {}", id)));
+        }
+        if byte[0] == SEPARATOR {
+            break;
+        }
+        header.push(byte[0]);
+        // "commit <len>" is the longest valid header by far
+        if header.len() > MAX_HEADER_LEN {
+            return Err(invalid(format!("Invalid header in object {}", id)));
+        }
+    }
+    return parse_header(id, &header);
+}
+
+impl Object {
+    pub fn type_(&self) -> ObjType {
+        match self {
+            Object::Blob(_) => ObjType::Blob,
+            Object::Tree(_) => ObjType::Tree,
+            Object::Commit(_) => ObjType::Commit,
+            Object::Tag(_) => ObjType::Tag,
+            Object::Chunks(_) => ObjType::Chunks,
+        }
+    }
+
+    // parses the payload of object id, which has the given type
+    pub fn parse(id: &ObjectId, type_: ObjType, data: Vec<u8>) -> Result<Object, IOError> {
+        match type_ {
+            ObjType::Blob => return Ok(Object::Blob(data)),
+            ObjType::Tree => return Ok(Object::Tree(parse_tree(id, &data)?)),
+            _ => {}
+        }
+        let text = String::from_utf8(data)
+            .map_err(|_| invalid(format!("{} {} is not valid utf-8", type_, id)))?;
+        match type_ {
+            ObjType::Blob | ObjType::Tree => unreachable!(),
+            ObjType::Commit => Ok(Object::Commit(parse_commit(id, &text)?)),
+            ObjType::Tag => Ok(Object::Tag(parse_tag(id, &text)?)),
+            ObjType::Chunks => Ok(Object::Chunks(parse_chunks(id, &text)?)),
+        }
+    }
+
+    // the payload without header
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Object::Blob(data) => data.clone(),
+            Object::Tree(entries) => serialize_tree(entries),
+            Object::Commit(c) => {
+                let parent = match &c.parent {
+                    Some(p) => p.to_string(),
+                    None => "initial".to_string(),
+                };
+                format!(
+                    "tree {}\nparent {}\nauthor {}\ntime {}\n{}",
+                    c.tree, parent, c.author, c.time, c.message
+                )
+                .into_bytes()
+            }
+            Object::Tag(t) => format!(
+                "object {}\ntype {}\ntag {}\ntagger {}\ntime {}\n{}",
+                t.object, t.type_, t.tag, t.tagger, t.time, t.message
+            )
+            .into_bytes(),
+            Object::Chunks(ids) => ids
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<&str>>()
+                .join("\n")
+                .into_bytes(),
+        }
+    }
+}
+
+// each entry is "<mode> <type> <id> <name>" ended by a NUL, file names can not contain NUL
+// so any other byte is allowed in them. entries are sorted by the bytes of their names so the
+// same directory always gives the same tree id
+pub fn serialize_tree(entries: &[FileData]) -> Vec<u8> {
+    let mut sorted = entries.iter().collect::<Vec<&FileData>>();
+    sorted.sort_by_cached_key(|x| name_to_bytes(&x.file_name));
+    let mut data: Vec<u8> = vec![];
+    for x in sorted {
+        data.extend_from_slice(format!("{} {} {} ", x.mode, x.file_type, x.hash).as_bytes());
+        data.extend_from_slice(&name_to_bytes(&x.file_name));
+        data.push(SEPARATOR);
+    }
+    return data;
+}
+
+fn parse_tree(id: &ObjectId, data: &[u8]) -> Result<Vec<FileData>, IOError> {
+    let mut entries: Vec<FileData> = Vec::new();
+    // empty directories are stored as empty trees
+    if data.is_empty() {
+        return Ok(entries);
+    }
+    // trees written before names were NUL terminated are newline separated text, in
+    // read_dir order
+    if data.last() != Some(&SEPARATOR) {
+        return parse_text_tree(id, data);
+    }
+    for record in data[..data.len() - 1].split(|x| *x == SEPARATOR) {
+        let mut fields = record.splitn(4, |x| *x == b' ');
+        let mut next_field = || {
+            fields
+                .next()
+                .ok_or_else(|| invalid(format!("Tree {}: entry is missing fields", id)))
+        };
+        let mode = next_field()?;
+        let file_type = next_field()?;
+        let hash = next_field()?;
+        let name = next_field()?;
+        if let Some(last) = entries.last() {
+            if name_to_bytes(&last.file_name).as_slice() >= name {
+                return Err(invalid(format!(
+                    "Tree {}: entries are not sorted or {:?} appears twice",
+                    id,
+                    name_from_bytes(name)
+                )));
+            }
+        }
+        let text = |x: &[u8]| String::from_utf8_lossy(x).into_owned();
+        entries.push(tree_entry(
+            id,
+            Some(&text(mode)),
+            &text(file_type),
+            &text(hash),
+            name_from_bytes(name),
+        )?);
+    }
+    return Ok(entries);
+}
+
+fn parse_text_tree(id: &ObjectId, data: &[u8]) -> Result<Vec<FileData>, IOError> {
+    let text = std::str::from_utf8(data)
+        .map_err(|_| invalid(format!("Tree {} is not valid utf-8", id)))?;
+    let mut entries: Vec<FileData> = Vec::new();
+    for line in text.split("\n") {
+        let d = line.split_ascii_whitespace().collect::<Vec<&str>>();
+        // the oldest trees have no mode column
+        let entry = match d.len() {
+            3 => tree_entry(id, None, d[0], d[1], OsString::from(d[2]))?,
+            4 => tree_entry(id, Some(d[0]), d[1], d[2], OsString::from(d[3]))?,
+            _ => {
+                return Err(invalid(format!(
+                    "Tree {}: expected 4 values got {}",
+                    id,
+                    d.len()
+                )))
+            }
+        };
+        entries.push(entry);
+    }
+    return Ok(entries);
+}
+
+// checks the fields of a tree entry, a missing mode is taken from the type
+fn tree_entry(
+    id: &ObjectId,
+    mode: Option<&str>,
+    file_type: &str,
+    hash: &str,
+    file_name: OsString,
+) -> Result<FileData, IOError> {
+    let file_type = match ObjType::parse(file_type) {
+        Some(x @ (ObjType::Tree | ObjType::Blob | ObjType::Chunks)) => x,
+        _ => {
+            return Err(invalid(format!(
+                "Incorrect type found in tree {}: {}. Expected 'tree', 'blob' or 'chunks'",
+                id, file_type
+            )))
+        }
+    };
+    let mode = match mode {
+        Some(x) => FileMode::parse(x)
+            .ok_or_else(|| invalid(format!("Invalid mode in tree {}: {}", id, x)))?,
+        None if file_type == ObjType::Tree => FileMode::Directory,
+        None => FileMode::Regular,
+    };
+    if !mode.allows(file_type) {
+        return Err(invalid(format!(
+            "Tree {}: {} entry {:?} can not have mode {}",
+            id, file_type, file_name, mode
+        )));
+    }
+    let hash = ObjectId::parse(hash)
+        .ok_or_else(|| invalid(format!("Invalid object id in tree {}: {}", id, hash)))?;
+    // names are joined onto the checkout path so they must stay a single component
+    let name = name_to_bytes(&file_name);
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        return Err(invalid(format!(
+            "Tree {}: invalid file name {:?}",
+            id, file_name
+        )));
+    }
+    return Ok(FileData {
+        file_name,
+        mode,
+        file_type,
+        hash,
+    });
+}
+
+#[cfg(unix)]
+pub fn name_to_bytes(name: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    return name.as_bytes().to_vec();
+}
+
+#[cfg(not(unix))]
+pub fn name_to_bytes(name: &OsStr) -> Vec<u8> {
+    return name.to_string_lossy().as_bytes().to_vec();
+}
+
+#[cfg(unix)]
+pub fn name_from_bytes(name: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    return OsStr::from_bytes(name).to_os_string();
+}
+
+#[cfg(not(unix))]
+pub fn name_from_bytes(name: &[u8]) -> OsString {
+    return OsString::from(String::from_utf8_lossy(name).into_owned());
+}
+
+// reads the value of the next "<name> <value>" line of a commit or tag
+fn field<'a>(
+    id: &ObjectId,
+    type_: ObjType,
+    lines: &mut std::str::SplitN<'a, char>,
+    name: &str,
+) -> Result<&'a str, IOError> {
+    match lines.next().and_then(|x| x.split_once(' ')) {
+        Some((key, value)) if key == name => Ok(value),
+        _ => Err(invalid(format!("Missing {} in {} {}", name, type_, id))),
+    }
+}
+
+fn id_field(
+    id: &ObjectId,
+    type_: ObjType,
+    lines: &mut std::str::SplitN<'_, char>,
+    name: &str,
+) -> Result<ObjectId, IOError> {
+    let value = field(id, type_, lines, name)?;
+    return ObjectId::parse(value).ok_or_else(|| {
+        invalid(format!(
+            "Invalid {} id in {} {}: {}",
+            name, type_, id, value
+        ))
+    });
+}
+
+fn parse_commit(id: &ObjectId, text: &str) -> Result<CommitData, IOError> {
+    let t = ObjType::Commit;
+    let mut lines = text.splitn(5, '\n');
+    let tree = id_field(id, t, &mut lines, "tree")?;
+    let parent = match field(id, t, &mut lines, "parent")? {
+        "initial" => None,
+        x => Some(
+            ObjectId::parse(x)
+                .ok_or_else(|| invalid(format!("Invalid parent id in commit {}: {}", id, x)))?,
+        ),
+    };
+    let author = field(id, t, &mut lines, "author")?.to_string();
+    let time = field(id, t, &mut lines, "time")?.to_string();
+    let message = lines.next().unwrap_or_default().to_string();
+    return Ok(CommitData {
+        tree,
+        parent,
+        author,
+        time,
+        message,
+    });
+}
+
+fn parse_tag(id: &ObjectId, text: &str) -> Result<TagData, IOError> {
+    let t = ObjType::Tag;
+    let mut lines = text.splitn(6, '\n');
+    let object = id_field(id, t, &mut lines, "object")?;
+    let type_name = field(id, t, &mut lines, "type")?;
+    let type_ = ObjType::parse(type_name)
+        .ok_or_else(|| invalid(format!("Unknown type {} in tag {}", type_name, id)))?;
+    let tag = field(id, t, &mut lines, "tag")?.to_string();
+    let tagger = field(id, t, &mut lines, "tagger")?.to_string();
+    let time = field(id, t, &mut lines, "time")?.to_string();
+    let message = lines.next().unwrap_or_default().to_string();
+    return Ok(TagData {
+        object,
+        type_,
+        tag,
+        tagger,
+        time,
+        message,
+    });
+}
+
+// one chunk id per line, in file order
+fn parse_chunks(id: &ObjectId, text: &str) -> Result<Vec<ObjectId>, IOError> {
+    if text.is_empty() {
+        return Ok(vec![]);
+    }
+    return text
+        .split("\n")
+        .map(|x| {
+            ObjectId::parse(x)
+                .ok_or_else(|| invalid(format!("Invalid chunk id in chunks {}: {}", id, x)))
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id() -> ObjectId {
+        return ObjectId::hash(b"test");
+    }
+
+    fn entry(name: &[u8], mode: FileMode, file_type: ObjType) -> FileData {
+        return FileData {
+            file_name: name_from_bytes(name),
+            mode,
+            file_type,
+            hash: ObjectId::hash(name),
+        };
+    }
+
+    fn parse(data: &[u8]) -> Result<Vec<FileData>, IOError> {
+        return match Object::parse(&id(), ObjType::Tree, data.to_vec())? {
+            Object::Tree(entries) => Ok(entries),
+            _ => unreachable!(),
+        };
+    }
+
+    // name, mode, type and id of every entry
+    fn fields(entries: &[FileData]) -> Vec<(Vec<u8>, FileMode, ObjType, ObjectId)> {
+        return entries
+            .iter()
+            .map(|x| {
+                let name = name_to_bytes(&x.file_name);
+                (name, x.mode, x.file_type, x.hash.clone())
+            })
+            .collect();
+    }
+
+    #[test]
+    fn any_file_name_round_trips() {
+        let mut names = vec![
+            b"with space".to_vec(),
+            b"new\nline".to_vec(),
+            b" leading and trailing ".to_vec(),
+            b"tab\tand\rreturn".to_vec(),
+            "\u{e9}t\u{e9} \u{1f980}".as_bytes().to_vec(),
+            b"...".to_vec(),
+        ];
+        if cfg!(unix) {
+            names.push(b"not utf-8 \xff\xfe\xc3\x28".to_vec());
+        }
+        let modes = [
+            (FileMode::Regular, ObjType::Blob),
+            (FileMode::Executable, ObjType::Chunks),
+            (FileMode::Symlink, ObjType::Blob),
+            (FileMode::Directory, ObjType::Tree),
+        ];
+        let entries = names
+            .iter()
+            .zip(modes.iter().cycle())
+            .map(|(name, (mode, type_))| entry(name, *mode, *type_))
+            .collect::<Vec<FileData>>();
+        let mut expected = fields(&entries);
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        let data = serialize_tree(&entries);
+        assert_eq!(fields(&parse(&data).unwrap()), expected);
+        assert!(parse(b"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_file_names_are_rejected() {
+        let hash = id().to_string();
+        for name in ["", ".", "..", "a/b", "/", "dir/"] {
+            let entry = tree_entry(&id(), Some("100644"), "blob", &hash, OsString::from(name));
+            assert!(entry.is_err(), "{:?}", name);
+            let data = format!("100644 blob {} {}\0", hash, name);
+            assert!(parse(data.as_bytes()).is_err(), "{:?}", name);
+        }
+        assert!(tree_entry(&id(), Some("100644"), "blob", &hash, OsString::from("..a")).is_ok());
+    }
+
+    #[test]
+    fn trees_must_be_sorted() {
+        let record = |name: &str| format!("100644 blob {} {}\0", ObjectId::hash(b"x"), name);
+        let sorted = [record("a"), record("b"), record("b.txt")].concat();
+        let names = parse(sorted.as_bytes())
+            .unwrap()
+            .iter()
+            .map(|x| x.file_name.clone())
+            .collect::<Vec<OsString>>();
+        assert_eq!(names, ["a", "b", "b.txt"]);
+
+        let unsorted = [record("b"), record("a")].concat();
+        assert!(parse(unsorted.as_bytes()).is_err());
+        let duplicate = [record("a"), record("b"), record("b")].concat();
+        assert!(parse(duplicate.as_bytes()).is_err());
+
+        // serializing sorts the entries whatever order they come in
+        let names = [b"b".as_slice(), b"a", b"B"];
+        let entries = names.map(|x| entry(x, FileMode::Regular, ObjType::Blob));
+        let reversed = names.map(|x| entry(x, FileMode::Regular, ObjType::Blob));
+        let reversed = reversed.into_iter().rev().collect::<Vec<FileData>>();
+        assert_eq!(serialize_tree(&entries), serialize_tree(&reversed));
+        assert!(parse(&serialize_tree(&entries)).is_ok());
+    }
+
+    #[test]
+    fn old_text_trees_are_read() {
+        let (blob, tree) = (ObjectId::hash(b"blob"), ObjectId::hash(b"tree"));
+        // in read_dir order, the oldest without modes
+        let data = format!(
+            "blob {} z\ntree {} dir\n100755 blob {} run.sh\n120000 blob {} link",
+            blob, tree, blob, blob
+        );
+        let expected = [
+            ("z", FileMode::Regular, ObjType::Blob, &blob),
+            ("dir", FileMode::Directory, ObjType::Tree, &tree),
+            ("run.sh", FileMode::Executable, ObjType::Blob, &blob),
+            ("link", FileMode::Symlink, ObjType::Blob, &blob),
+        ]
+        .map(|(name, mode, type_, hash)| (name.as_bytes().to_vec(), mode, type_, hash.clone()));
+        assert_eq!(fields(&parse(data.as_bytes()).unwrap()), expected);
+
+        let bad = [
+            format!("blob {}", blob),
+            format!("commit {} a", blob),
+            format!("100644 tree {} a", tree),
+            format!("blob {} ..", blob),
+            "blob 1234 a".to_string(),
+        ];
+        for data in bad {
+            assert!(parse(data.as_bytes()).is_err(), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn bad_headers_are_errors() {
+        let bytes = encode(ObjType::Commit, b"payload");
+        assert_eq!(&bytes[..10], b"commit 7\0p");
+        let (type_, data) = decode(&id(), bytes.clone()).unwrap();
+        assert_eq!(
+            (type_, data.as_slice()),
+            (ObjType::Commit, b"payload".as_slice())
+        );
+        let mut reader = bytes.as_slice();
+        assert_eq!(
+            read_header(&id(), &mut reader).unwrap(),
+            (ObjType::Commit, 7)
+        );
+        assert_eq!(reader, b"payload");
+
+        for header in [
+            b"".as_slice(),
+            b"blob",
+            b"blob ",
+            b"blob x",
+            b"blob -1",
+            b"blob 1 2",
+            b"nope 1",
+            b" 1",
+            b"\xff\xfe 1",
+        ] {
+            assert!(parse_header(&id(), header).is_err(), "{:?}", header);
+            let mut stored = header.to_vec();
+            stored.push(SEPARATOR);
+            assert!(decode(&id(), stored.clone()).is_err(), "{:?}", header);
+            assert!(
+                read_header(&id(), &mut stored.as_slice()).is_err(),
+                "{:?}",
+                header
+            );
+        }
+
+        // no separator
+        assert!(decode(&id(), b"blob 3abc".to_vec()).is_err());
+        assert!(read_header(&id(), &mut b"blob 3".as_slice()).is_err());
+        // read_header gives up instead of reading a whole file looking for the separator
+        let overlong = format!("blob {}\0", "1".repeat(MAX_HEADER_LEN));
+        assert!(read_header(&id(), &mut overlong.as_bytes()).is_err());
+        // the payload is shorter or longer than the header says
+        for len in [6, 8] {
+            let stored = [format!("blob {}\0", len).as_bytes(), b"payload"].concat();
+            assert!(decode(&id(), stored).is_err(), "{}", len);
+        }
+    }
+}
@@ -0,0 +1,585 @@
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Error as IOError;
+use std::io::ErrorKind as IOErrorKind;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::data::{self, ObjectId};
+use crate::object::{self, ObjType};
+use crate::store::{FsStore, ObjectStore};
+
+// pack layout (all integers are big endian):
+//   .pack: "YPCK" version:u32 count:u32 then count entries of
+//          kind:u8 [base id:32 bytes if kind is DELTA] len:u64 zlib data:len bytes
+//   .idx:  "YIDX" version:u32 count:u32 then count entries of id:32 bytes offset:u64 sorted by id
+// the zlib data is the full object (header included) for FULL entries and a delta against
+// the base object for DELTA entries. bases are always stored in the same pack.
+pub const PACK_DIR: &str = "./.yeet/objects/pack";
+const PACK_MAGIC: &[u8; 4] = b"YPCK";
+const INDEX_MAGIC: &[u8; 4] = b"YIDX";
+const PACK_VERSION: u32 = 1;
+const FULL: u8 = 0;
+const DELTA: u8 = 1;
+
+// number of previous blobs each blob is compared against when looking for a delta base
+const DELTA_WINDOW: usize = 10;
+// longest chain of deltas that has to be resolved to read an object
+const MAX_DELTA_DEPTH: usize = 16;
+// size of the blocks matched between base and target
+const DELTA_BLOCK: usize = 16;
+
+// delta ops
+const COPY: u8 = 0;
+const INSERT: u8 = 1;
+
+pub struct PackIndex {
+    pub pack_path: PathBuf,
+    pub entries: Vec<(ObjectId, u64)>,
+}
+
+impl PackIndex {
+    fn find(&self, hash: &ObjectId) -> Option<u64> {
+        self.entries
+            .binary_search_by(|x| x.0.cmp(hash))
+            .ok()
+            .map(|i| self.entries[i].1)
+    }
+}
+
+// indexes of all packs, loaded on first use and dropped after a repack
+static PACKS: Mutex<Option<Arc<Vec<PackIndex>>>> = Mutex::new(None);
+
+fn get_packs() -> Result<Arc<Vec<PackIndex>>, IOError> {
+    let mut packs = PACKS.lock().unwrap();
+    if let Some(p) = packs.as_ref() {
+        return Ok(p.clone());
+    }
+    let loaded = Arc::new(load_packs()?);
+    *packs = Some(loaded.clone());
+    return Ok(loaded);
+}
+
+pub(crate) fn reset_packs() {
+    *PACKS.lock().unwrap() = None;
+}
+
+fn load_packs() -> Result<Vec<PackIndex>, IOError> {
+    let mut packs = vec![];
+    let dir = match fs::read_dir(PACK_DIR) {
+        Ok(d) => d,
+        Err(e) if e.kind() == IOErrorKind::NotFound => return Ok(packs),
+        Err(e) => return Err(e),
+    };
+    for i in dir {
+        let path = i?.path();
+        if path.extension().is_some_and(|x| x == "idx") {
+            packs.push(read_index(path)?);
+        }
+    }
+    return Ok(packs);
+}
+
+fn read_index(idx_path: PathBuf) -> Result<PackIndex, IOError> {
+    let bytes = fs::read(&idx_path)?;
+    let bad_index = || {
+        IOError::new(
+            IOErrorKind::InvalidData,
+            format!("Invalid pack index {:?}", idx_path),
+        )
+    };
+    if bytes.len() < 12 || &bytes[..4] != INDEX_MAGIC {
+        return Err(bad_index());
+    }
+    if u32::from_be_bytes(bytes[4..8].try_into().unwrap()) != PACK_VERSION {
+        return Err(bad_index());
+    }
+    let count = u32::from_be_bytes(bytes[8..12].try_into().unwrap()) as usize;
+    if bytes.len() != 12 + count * 40 {
+        return Err(bad_index());
+    }
+    let entries = bytes[12..]
+        .chunks_exact(40)
+        .map(|x| {
+            let id = ObjectId::from_bytes(x[..32].try_into().unwrap());
+            let offset = u64::from_be_bytes(x[32..].try_into().unwrap());
+            (id, offset)
+        })
+        .collect::<Vec<_>>();
+    return Ok(PackIndex {
+        pack_path: idx_path.with_extension("pack"),
+        entries,
+    });
+}
+
+// reads an object out of whichever pack holds it, None if no pack does
+pub fn read_packed(hash: &ObjectId) -> Result<Option<Vec<u8>>, IOError> {
+    let packs = get_packs()?;
+    for pack in packs.iter() {
+        if let Some(offset) = pack.find(hash) {
+            let mut file = File::open(&pack.pack_path)?;
+            return Ok(Some(read_entry(pack, &mut file, offset, 0)?));
+        }
+    }
+    return Ok(None);
+}
+
+// removes every pack except keep
+fn remove_packs(keep: Option<&PathBuf>) -> Result<(), IOError> {
+    for old in get_packs()?.iter() {
+        if Some(&old.pack_path) != keep {
+            fs::remove_file(old.pack_path.with_extension("idx"))?;
+            fs::remove_file(&old.pack_path)?;
+        }
+    }
+    reset_packs();
+    Ok(())
+}
+
+// modification time of the pack holding an object, None if no pack does
+pub fn packed_mtime(hash: &ObjectId) -> Result<Option<SystemTime>, IOError> {
+    for pack in get_packs()?.iter() {
+        if pack.find(hash).is_some() {
+            return Ok(Some(fs::metadata(&pack.pack_path)?.modified()?));
+        }
+    }
+    return Ok(None);
+}
+
+// writes a packed object out as a loose object as old as its pack, unless it is not packed or
+// already has a loose copy
+fn unpack(hash: &ObjectId) -> Result<(), IOError> {
+    let obj_path = data::object_path(hash);
+    if obj_path.exists() {
+        return Ok(());
+    }
+    for pack in get_packs()?.iter() {
+        if let Some(offset) = pack.find(hash) {
+            let mtime = fs::metadata(&pack.pack_path)?.modified()?;
+            let bytes = read_entry(pack, &mut File::open(&pack.pack_path)?, offset, 0)?;
+            fs::create_dir_all(obj_path.parent().unwrap())?;
+            data::write_atomic(&obj_path, &data::compress(&bytes)?)?;
+            File::options()
+                .write(true)
+                .open(&obj_path)?
+                .set_modified(mtime)?;
+            return Ok(());
+        }
+    }
+    return Ok(());
+}
+
+pub fn is_packed(hash: &ObjectId) -> Result<bool, IOError> {
+    return Ok(get_packs()?.iter().any(|x| x.find(hash).is_some()));
+}
+
+// ids of every packed object
+pub fn packed_ids() -> Result<Vec<ObjectId>, IOError> {
+    let packs = get_packs()?;
+    return Ok(packs
+        .iter()
+        .flat_map(|x| x.entries.iter().map(|y| y.0.clone()))
+        .collect());
+}
+
+fn read_entry(
+    pack: &PackIndex,
+    file: &mut File,
+    offset: u64,
+    depth: usize,
+) -> Result<Vec<u8>, IOError> {
+    if depth > MAX_DELTA_DEPTH {
+        return Err(IOError::new(
+            IOErrorKind::InvalidData,
+            format!("Delta chain too long in {:?}", pack.pack_path),
+        ));
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut kind = [0u8; 1];
+    file.read_exact(&mut kind)?;
+    let mut base = None;
+    if kind[0] == DELTA {
+        let mut base_id = [0u8; 32];
+        file.read_exact(&mut base_id)?;
+        base = Some(ObjectId::from_bytes(&base_id));
+    } else if kind[0] != FULL {
+        return Err(IOError::new(
+            IOErrorKind::InvalidData,
+            format!("Unknown entry kind {} in {:?}", kind[0], pack.pack_path),
+        ));
+    }
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len)?;
+    let mut data = Vec::new();
+    ZlibDecoder::new(file.take(u64::from_be_bytes(len))).read_to_end(&mut data)?;
+
+    match base {
+        None => Ok(data),
+        Some(base) => {
+            let base_offset = pack.find(&base).ok_or(IOError::new(
+                IOErrorKind::InvalidData,
+                format!("Delta base {} missing from {:?}", base, pack.pack_path),
+            ))?;
+            let base_data = read_entry(pack, file, base_offset, depth + 1)?;
+            apply_delta(&base_data, &data)
+        }
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, IOError> {
+    let mut x = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos).ok_or(bad_delta())?;
+        *pos += 1;
+        if shift > 63 {
+            return Err(bad_delta());
+        }
+        x |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(x);
+        }
+        shift += 7;
+    }
+}
+
+fn bad_delta() -> IOError {
+    IOError::new(IOErrorKind::InvalidData, "Invalid delta in pack")
+}
+
+// delta layout: target_len:varint then ops of
+//   COPY offset:varint len:varint   copies len bytes of the base starting at offset
+//   INSERT len:varint bytes         copies the next len bytes of the delta
+fn make_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in base.chunks_exact(DELTA_BLOCK).enumerate() {
+        blocks.entry(block).or_insert(i * DELTA_BLOCK);
+    }
+
+    let mut delta = vec![];
+    write_varint(&mut delta, target.len() as u64);
+    let mut pending: Vec<u8> = vec![];
+    let flush = |delta: &mut Vec<u8>, pending: &mut Vec<u8>| {
+        if !pending.is_empty() {
+            delta.push(INSERT);
+            write_varint(delta, pending.len() as u64);
+            delta.append(pending);
+        }
+    };
+
+    let mut i = 0;
+    while i < target.len() {
+        let matched = target
+            .get(i..i + DELTA_BLOCK)
+            .and_then(|x| blocks.get(x))
+            .map(|&start| {
+                let len = base[start..]
+                    .iter()
+                    .zip(&target[i..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (start, len)
+            });
+        match matched {
+            Some((start, len)) => {
+                flush(&mut delta, &mut pending);
+                delta.push(COPY);
+                write_varint(&mut delta, start as u64);
+                write_varint(&mut delta, len as u64);
+                i += len;
+            }
+            None => {
+                pending.push(target[i]);
+                i += 1;
+            }
+        }
+    }
+    flush(&mut delta, &mut pending);
+    return delta;
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, IOError> {
+    let mut pos = 0;
+    let target_len = read_varint(delta, &mut pos)? as usize;
+    let mut target = Vec::with_capacity(target_len);
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op == COPY {
+            let start = read_varint(delta, &mut pos)? as usize;
+            let len = read_varint(delta, &mut pos)? as usize;
+            let end = start.checked_add(len).ok_or(bad_delta())?;
+            target.extend_from_slice(base.get(start..end).ok_or(bad_delta())?);
+        } else if op == INSERT {
+            let len = read_varint(delta, &mut pos)? as usize;
+            let end = pos.checked_add(len).ok_or(bad_delta())?;
+            target.extend_from_slice(delta.get(pos..end).ok_or(bad_delta())?);
+            pos = end;
+        } else {
+            return Err(bad_delta());
+        }
+    }
+    if target.len() != target_len {
+        return Err(bad_delta());
+    }
+    return Ok(target);
+}
+
+// the last DELTA_WINDOW blobs written to a pack, the only ones held in memory while packing
+struct DeltaWindow {
+    // id, data and delta depth of each blob
+    blobs: VecDeque<(ObjectId, Vec<u8>, usize)>,
+}
+
+impl DeltaWindow {
+    // picks the blob in the window that gives the smallest delta for data, if any is worth it,
+    // and adds data to the window. returns the base id and the delta
+    fn add(&mut self, id: &ObjectId, data: Vec<u8>) -> Option<(ObjectId, Vec<u8>)> {
+        let mut best: Option<(ObjectId, Vec<u8>, usize)> = None;
+        for (base_id, base_data, depth) in &self.blobs {
+            if *depth >= MAX_DELTA_DEPTH {
+                continue;
+            }
+            let delta = make_delta(base_data, &data);
+            // only worth it when the delta is much smaller than the blob itself
+            if delta.len() < data.len() / 2 && best.as_ref().is_none_or(|x| delta.len() < x.1.len())
+            {
+                best = Some((base_id.clone(), delta, *depth));
+            }
+        }
+        let depth = best.as_ref().map_or(0, |x| x.2 + 1);
+        self.blobs.push_back((id.clone(), data, depth));
+        if self.blobs.len() > DELTA_WINDOW {
+            self.blobs.pop_front();
+        }
+        return best.map(|x| (x.0, x.1));
+    }
+}
+
+// appends entries to a pack file, keeping track of where each one starts
+struct PackWriter<W: Write> {
+    out: W,
+    offset: u64,
+    level: flate2::Compression,
+}
+
+impl<W: Write> PackWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), IOError> {
+        self.out.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    // writes an entry and returns its offset, data is a delta when base is set
+    fn entry(&mut self, base: Option<&ObjectId>, data: &[u8]) -> Result<u64, IOError> {
+        let offset = self.offset;
+        match base {
+            Some(base) => {
+                self.write(&[DELTA])?;
+                self.write(&base.to_bytes())?;
+            }
+            None => self.write(&[FULL])?,
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), self.level);
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        self.write(&(compressed.len() as u64).to_be_bytes())?;
+        self.write(&compressed)?;
+        return Ok(offset);
+    }
+}
+
+// writes all reachable objects and the extra ones into a single new pack, then removes the
+// old packs and the loose copies of packed objects. packed objects that are neither reachable
+// nor in extra are dropped, unless they are in loosen: those are written back as loose objects
+// that keep the modification time of their pack, so repacking does not reset their age.
+// objects are read and written one at a time, only the delta window is kept in memory.
+// returns the number of packed objects and how many of them were stored as deltas
+pub fn repack(
+    store: &FsStore,
+    extra: &[ObjectId],
+    loosen: &[ObjectId],
+) -> Result<(usize, usize), IOError> {
+    let mut names: Vec<(ObjectId, OsString)> = data::reachable_objects(store)?
+        .into_iter()
+        .map(|x| (x.id, x.name))
+        .collect();
+    let mut seen = names.iter().map(|x| x.0.clone()).collect::<HashSet<_>>();
+    for id in extra {
+        if seen.insert(id.clone()) {
+            names.push((id.clone(), OsString::new()));
+        }
+    }
+    for id in loosen {
+        if !seen.contains(id) {
+            unpack(id)?;
+        }
+    }
+    if names.is_empty() {
+        remove_packs(None)?;
+        return Ok((0, 0));
+    }
+
+    // only the headers are read here
+    let mut others = vec![];
+    let mut blobs = vec![];
+    for (id, name) in names {
+        let (type_, len) = object::read_header(&id, &mut store.open(&id)?)?;
+        if type_ == ObjType::Blob {
+            blobs.push((name, len, id));
+        } else {
+            others.push(id);
+        }
+    }
+    // blobs are sorted by the name they were found under so revisions of the same file end up
+    // in the same delta window
+    blobs.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+    let mut ids = others
+        .iter()
+        .chain(blobs.iter().map(|x| &x.2))
+        .cloned()
+        .collect::<Vec<_>>();
+    ids.sort();
+    // the pack is named after the ids it holds
+    let pack_name = format!(
+        "pack-{}",
+        ObjectId::hash(
+            ids.iter()
+                .map(|x| x.as_str())
+                .collect::<String>()
+                .as_bytes()
+        )
+    );
+    fs::create_dir_all(PACK_DIR)?;
+    let pack_path = PathBuf::from(PACK_DIR).join(format!("{}.pack", pack_name));
+    let idx_path = PathBuf::from(PACK_DIR).join(format!("{}.idx", pack_name));
+
+    let mut index: Vec<(ObjectId, u64)> = vec![];
+    let mut deltas = 0;
+    let level = flate2::Compression::new(data::get_compression()?.level);
+    data::write_atomic_with(&pack_path, |file| {
+        let mut pack = PackWriter {
+            out: BufWriter::new(file),
+            offset: 0,
+            level,
+        };
+        pack.write(PACK_MAGIC)?;
+        pack.write(&PACK_VERSION.to_be_bytes())?;
+        pack.write(&(ids.len() as u32).to_be_bytes())?;
+        for id in &others {
+            let offset = pack.entry(None, &store.read(id)?)?;
+            index.push((id.clone(), offset));
+        }
+        let mut window = DeltaWindow {
+            blobs: VecDeque::new(),
+        };
+        for (_, _, id) in &blobs {
+            let bytes = store.read(id)?;
+            let offset = match window.add(id, bytes) {
+                Some((base, delta)) => {
+                    deltas += 1;
+                    pack.entry(Some(&base), &delta)?
+                }
+                // the window holds the blob it was just given
+                None => pack.entry(None, &window.blobs.back().unwrap().1)?,
+            };
+            index.push((id.clone(), offset));
+        }
+        pack.out.flush()
+    })?;
+    index.sort();
+
+    let mut idx = vec![];
+    idx.extend_from_slice(INDEX_MAGIC);
+    idx.extend_from_slice(&PACK_VERSION.to_be_bytes());
+    idx.extend_from_slice(&(index.len() as u32).to_be_bytes());
+    for (id, offset) in &index {
+        idx.extend_from_slice(&id.to_bytes());
+        idx.extend_from_slice(&offset.to_be_bytes());
+    }
+    // the index is written last so readers never see an index without its pack
+    data::write_atomic(&idx_path, &idx)?;
+
+    remove_packs(Some(&pack_path))?;
+
+    // removes only the loose copies, the packed ones were just written
+    for (id, _) in &index {
+        store.remove(id)?;
+    }
+
+    return Ok((index.len(), deltas));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() {
+        let base = (0..5000u32)
+            .map(|x| (x.wrapping_mul(2654435761) >> 24) as u8)
+            .collect::<Vec<u8>>();
+        let mut edited = base.clone();
+        edited.splice(2000..2100, b"inserted in the middle\0".iter().cloned());
+        edited.extend_from_slice(&base[..300]);
+        let cases: [(&[u8], &[u8]); 6] = [
+            (&base, &base),
+            (&base, &edited),
+            (&edited, &base),
+            (b"", &base),
+            (&base, b""),
+            (b"short", b"\xffnot related at all"),
+        ];
+        for (base, target) in cases {
+            let delta = make_delta(base, target);
+            assert_eq!(apply_delta(base, &delta).unwrap(), target);
+        }
+        // unchanged blocks are copied instead of inserted
+        assert!(make_delta(&base, &edited).len() < 200);
+    }
+
+    #[test]
+    fn bad_delta_is_an_error() {
+        let delta = make_delta(b"0123456789abcdef0123", b"0123456789abcdef!");
+        assert!(apply_delta(b"0123", &delta).is_err());
+        assert!(apply_delta(b"", &[5, INSERT, 2, b'a', b'b']).is_err());
+        assert!(apply_delta(b"", &[1, 7]).is_err());
+    }
+
+    #[test]
+    fn delta_window_is_bounded() {
+        let mut window = DeltaWindow {
+            blobs: VecDeque::new(),
+        };
+        let blob = |i: usize| {
+            let mut x = vec![b'x'; 4096];
+            x[i] = b'y';
+            x
+        };
+        let id = |i: usize| ObjectId::hash(&blob(i));
+        assert!(window.add(&id(0), blob(0)).is_none());
+        for i in 1..DELTA_WINDOW * 3 {
+            let candidates = window.blobs.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+            let (base, delta) = window.add(&id(i), blob(i)).unwrap();
+            assert!(candidates.contains(&base));
+            assert!(delta.len() < 100);
+            assert!(window.blobs.len() <= DELTA_WINDOW);
+        }
+        // chains stop at MAX_DELTA_DEPTH
+        assert!(window.blobs.iter().all(|x| x.2 <= MAX_DELTA_DEPTH));
+    }
+}
@@ -0,0 +1,267 @@
+use flate2::bufread::ZlibDecoder;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error as IOError;
+use std::io::ErrorKind as IOErrorKind;
+use std::io::{BufRead, BufReader, Cursor, Read};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::data::{self, ObjectId, OBJECTS_DIR};
+use crate::pack;
+
+// somewhere objects can be kept. objects go in and come out uncompressed, with their
+// "<type> <len>" header, exactly as they were hashed. stores are shared between the threads
+// hashing a tree
+pub trait ObjectStore: Sync {
+    fn read(&self, id: &ObjectId) -> Result<Vec<u8>, IOError>;
+
+    // does nothing if the object already exists
+    fn write(&self, id: &ObjectId, object: &[u8]) -> Result<(), IOError>;
+
+    fn exists(&self, id: &ObjectId) -> Result<bool, IOError>;
+
+    // reads an object piece by piece instead of loading it whole
+    fn open(&self, id: &ObjectId) -> Result<Box<dyn Read + '_>, IOError> {
+        return Ok(Box::new(Cursor::new(self.read(id)?)));
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>, IOError>;
+
+    // when the object was stored, gc only prunes unreachable objects older than its cutoff
+    fn modified(&self, id: &ObjectId) -> Result<SystemTime, IOError>;
+
+    // removes an object, does nothing if it does not exist. packed objects are only dropped
+    // by the next repack
+    fn remove(&self, id: &ObjectId) -> Result<(), IOError>;
+
+    // packs the objects reachable from refs and the index along with extra and drops other
+    // packed objects, except the ones in loosen which are kept unpacked. returns the number of
+    // packed objects and how many of them are deltas. stores without packs keep everything
+    fn repack(&self, _extra: &[ObjectId], _loosen: &[ObjectId]) -> Result<(usize, usize), IOError> {
+        return Ok((0, 0));
+    }
+
+    // whether this is the repo's own store in ./.yeet/objects. the ids in other files of the
+    // repo, like the stat cache, only point at objects in that store
+    fn is_repo(&self) -> bool {
+        return false;
+    }
+
+    // sorted ids of all objects starting with prefix
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>, IOError> {
+        let mut ids = self
+            .iter()?
+            .filter(|x| x.as_str().starts_with(prefix))
+            .collect::<Vec<ObjectId>>();
+        ids.sort();
+        return Ok(ids);
+    }
+}
+
+fn not_found(id: &ObjectId) -> IOError {
+    IOError::new(IOErrorKind::NotFound, format!("Object {} not found", id))
+}
+
+// the repo's object store in ./.yeet/objects: compressed loose objects in fan-out
+// directories, falling back to packs
+pub struct FsStore;
+
+impl ObjectStore for FsStore {
+    fn read(&self, id: &ObjectId) -> Result<Vec<u8>, IOError> {
+        match fs::read(data::object_path(id)) {
+            Ok(bytes) => data::decompress(bytes),
+            Err(e) if e.kind() == IOErrorKind::NotFound => {
+                pack::read_packed(id)?.ok_or_else(|| not_found(id))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write(&self, id: &ObjectId, object: &[u8]) -> Result<(), IOError> {
+        // objects are immutable so an existing copy never needs to be rewritten
+        if self.exists(id)? {
+            return Ok(());
+        }
+        let stored = data::compress(object)?;
+        let obj_path = data::object_path(id);
+        fs::create_dir_all(obj_path.parent().unwrap())?;
+        return data::write_atomic(&obj_path, &stored);
+    }
+
+    fn exists(&self, id: &ObjectId) -> Result<bool, IOError> {
+        return Ok(data::object_path(id).exists() || pack::is_packed(id)?);
+    }
+
+    // packed objects are still read whole
+    fn open(&self, id: &ObjectId) -> Result<Box<dyn Read + '_>, IOError> {
+        match fs::File::open(data::object_path(id)) {
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                // same check as data::decompress
+                if reader.fill_buf()?.first() == Some(&0x78) {
+                    return Ok(Box::new(ZlibDecoder::new(reader)));
+                }
+                return Ok(Box::new(reader));
+            }
+            Err(e) if e.kind() == IOErrorKind::NotFound => {
+                let bytes = pack::read_packed(id)?.ok_or_else(|| not_found(id))?;
+                return Ok(Box::new(Cursor::new(bytes)));
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>, IOError> {
+        let mut ids = data::loose_object_ids()?;
+        ids.append(&mut pack::packed_ids()?);
+        ids.sort();
+        ids.dedup();
+        return Ok(Box::new(ids.into_iter()));
+    }
+
+    fn modified(&self, id: &ObjectId) -> Result<SystemTime, IOError> {
+        match fs::metadata(data::object_path(id)) {
+            Ok(x) => x.modified(),
+            Err(e) if e.kind() == IOErrorKind::NotFound => {
+                pack::packed_mtime(id)?.ok_or_else(|| not_found(id))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // removes the loose copy
+    fn remove(&self, id: &ObjectId) -> Result<(), IOError> {
+        let obj_path = data::object_path(id);
+        match fs::remove_file(&obj_path) {
+            Err(e) if e.kind() != IOErrorKind::NotFound => return Err(e),
+            // fails while the fan-out directory still holds other objects
+            _ => _ = fs::remove_dir(obj_path.parent().unwrap()),
+        }
+        Ok(())
+    }
+
+    fn repack(&self, extra: &[ObjectId], loosen: &[ObjectId]) -> Result<(usize, usize), IOError> {
+        return pack::repack(self, extra, loosen);
+    }
+
+    fn is_repo(&self) -> bool {
+        return true;
+    }
+
+    // only lists the fan-out directory the prefix falls in, prefix must be at least 2 characters
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>, IOError> {
+        let (dir, rest) = prefix.split_at(2);
+        let mut ids: Vec<ObjectId> = vec![];
+        match fs::read_dir(PathBuf::from(OBJECTS_DIR).join(dir)) {
+            Ok(entries) => {
+                for i in entries {
+                    let file_name = i?.file_name();
+                    let file_name = file_name.to_string_lossy();
+                    if file_name.starts_with(rest) {
+                        if let Some(id) = ObjectId::parse(&format!("{}{}", dir, file_name)) {
+                            ids.push(id);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == IOErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        for id in pack::packed_ids()? {
+            if id.as_str().starts_with(prefix) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        ids.dedup();
+        return Ok(ids);
+    }
+}
+
+// objects kept in memory, for embedding yeet without touching the disk
+#[derive(Default)]
+pub struct MemoryStore {
+    // each object along with when it was written
+    objects: Mutex<HashMap<ObjectId, (Vec<u8>, SystemTime)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn read(&self, id: &ObjectId) -> Result<Vec<u8>, IOError> {
+        let objects = self.objects.lock().unwrap();
+        return objects
+            .get(id)
+            .map(|x| x.0.clone())
+            .ok_or_else(|| not_found(id));
+    }
+
+    fn write(&self, id: &ObjectId, object: &[u8]) -> Result<(), IOError> {
+        let mut objects = self.objects.lock().unwrap();
+        objects
+            .entry(id.clone())
+            .or_insert_with(|| (object.to_vec(), SystemTime::now()));
+        Ok(())
+    }
+
+    fn exists(&self, id: &ObjectId) -> Result<bool, IOError> {
+        return Ok(self.objects.lock().unwrap().contains_key(id));
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>, IOError> {
+        let mut ids = self
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<ObjectId>>();
+        ids.sort();
+        return Ok(Box::new(ids.into_iter()));
+    }
+
+    fn modified(&self, id: &ObjectId) -> Result<SystemTime, IOError> {
+        let objects = self.objects.lock().unwrap();
+        return objects.get(id).map(|x| x.1).ok_or_else(|| not_found(id));
+    }
+
+    fn remove(&self, id: &ObjectId) -> Result<(), IOError> {
+        self.objects.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+// drops everything written to it, for finding the id a file would get without storing it
+pub struct NullStore;
+
+impl ObjectStore for NullStore {
+    fn read(&self, id: &ObjectId) -> Result<Vec<u8>, IOError> {
+        return Err(not_found(id));
+    }
+
+    fn write(&self, _id: &ObjectId, _object: &[u8]) -> Result<(), IOError> {
+        Ok(())
+    }
+
+    fn exists(&self, _id: &ObjectId) -> Result<bool, IOError> {
+        return Ok(false);
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>, IOError> {
+        return Ok(Box::new(std::iter::empty()));
+    }
+
+    fn modified(&self, id: &ObjectId) -> Result<SystemTime, IOError> {
+        return Err(not_found(id));
+    }
+
+    fn remove(&self, _id: &ObjectId) -> Result<(), IOError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+// helpers shared by the tests
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{pack, yeet};
+
+// a directory under the system temp dir, removed again on drop
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "yeet_test_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        return TempDir { path };
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// repos are found through the current directory, which the whole process shares
+static CWD: Mutex<()> = Mutex::new(());
+
+// a fresh repo that is the current directory for as long as it lives
+pub struct TempRepo {
+    _dir: TempDir,
+    old_cwd: PathBuf,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl TempRepo {
+    pub fn new() -> TempRepo {
+        // a failed test must not fail the ones after it
+        let lock = CWD.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = TempDir::new();
+        let old_cwd = env::current_dir().unwrap();
+        env::set_current_dir(&dir.path).unwrap();
+        // packs found in the last repo are cached
+        pack::reset_packs();
+        yeet::init_repo();
+        fs::write("./.yeet/repo_data/author", "test").unwrap();
+        return TempRepo {
+            _dir: dir,
+            old_cwd,
+            _lock: lock,
+        };
+    }
+}
+
+impl Drop for TempRepo {
+    fn drop(&mut self) {
+        _ = env::set_current_dir(&self.old_cwd);
+    }
+}
+
+// writes a file relative to the current directory, creating its parents
+pub fn write(path: impl AsRef<Path>, contents: &str) {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    fs::write(path, contents).unwrap();
+}
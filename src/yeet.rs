@@ -1,15 +1,31 @@
-use std::{env, fs, io::Write, path::PathBuf, process::exit};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    io::Write,
+    path::{Component, Path, PathBuf},
+    process::exit,
+    time::{Duration, SystemTime},
+};
 
+use rayon::prelude::*;
 use time::OffsetDateTime;
 
-use crate::data::{self, FileData};
+use crate::cache::{self, StatCache};
+use crate::data::{self, DirEntry, ObjectId};
+use crate::ignore::{self, Ignore};
+use crate::index::{Index, IndexEntry, Stat};
+use crate::merge;
+use crate::object::{self, CommitData, FileData, FileMode, ObjType, Object};
+use crate::store::{NullStore, ObjectStore};
 
 pub fn init_repo() {
     const INITIAL_HEAD: &[u8] = "initial".as_bytes();
     let res = fs::create_dir("./.yeet");
     match res {
         Err(e) => {
-            println!("Error creating directory: {}", e.to_string());
+            println!("Error creating directory: {}", e);
             return;
         }
         Ok(_) => {
@@ -20,23 +36,38 @@ pub fn init_repo() {
         }
     }
 
-    fs::create_dir("./.yeet/objects").expect("Error creating objects");
+    fs::create_dir(data::OBJECTS_DIR).expect("Error creating objects");
 
     fs::create_dir("./.yeet/repo_data").expect("Error creating repo_data");
 
+    fs::write("./.yeet/repo_data/hash_algorithm", data::HASH_ALGORITHM)
+        .expect("Error recording hash algorithm");
+
+    fs::write("./.yeet/repo_data/object_layout", data::OBJECT_LAYOUT)
+        .expect("Error recording object layout");
+
+    let compression = data::Compression::DEFAULT;
+    data::set_config(
+        "compression",
+        &format!("{:?}", compression.codec).to_lowercase(),
+    )
+    .expect("Error setting compression");
+    data::set_config("compression_level", &compression.level.to_string())
+        .expect("Error setting compression");
+
     fs::create_dir_all("./.yeet/refs/tags").expect("Error creating tags");
 
     let mut head = fs::File::create("./.yeet/refs/HEAD").expect("Error setting head");
 
-    head.write(INITIAL_HEAD).expect("Error setting head");
+    head.write_all(INITIAL_HEAD).expect("Error setting head");
 }
 
-pub fn cat_file(hash: &String) {
-    let res = data::get_data(hash, String::from("./.yeet/objects/"));
+pub fn cat_file(store: &dyn ObjectStore, hash: &String) {
+    let res = data::get_actual_hash(store, hash).and_then(|x| data::get_data(store, &x));
     match res {
         Ok(data) => {
             // data = [data_type, file_data]
-            println!("obj-type: {}", String::from_utf8(data.file_type).unwrap());
+            println!("obj-type: {}", data.file_type);
             println!("file-data: {:?}", data.file_data);
             if let Ok(file_data) = String::from_utf8(data.file_data) {
                 println!("ascii:\n{}", file_data);
@@ -50,153 +81,1744 @@ pub fn cat_file(hash: &String) {
     }
 }
 
-pub fn hash_file(path: PathBuf, show_out: bool) -> Result<u64, std::io::Error> {
-    let file_data = fs::read(&path)?;
-    // println!("{:?}", file_data);
-    let hash = data::write_obj_hash(&file_data, "blob".to_string())?;
+pub fn hash_file(
+    store: &dyn ObjectStore,
+    path: PathBuf,
+    show_out: bool,
+) -> Result<(ObjType, ObjectId), std::io::Error> {
+    let mut file = fs::File::open(&path)?;
+    let (type_, hash) = data::write_file(store, &mut file)?;
 
     if show_out {
-        println!("blob {} {:?}", hash, path.file_name().unwrap());
+        println!("{} {} {:?}", type_, hash, path.file_name().unwrap());
     }
-    return Ok(hash);
+    return Ok((type_, hash));
 }
 
-pub fn write_tree(path: PathBuf) -> Result<u64, std::io::Error> {
-    let dir_entries = fs::read_dir(path.clone()).expect("Failed to read directory");
-    let mut cur_dir_data: Vec<FileData> = vec![];
+// path has to be the repo root since the stat cache is keyed by paths relative to it.
+// files are hashed on jobs threads, or as many as the jobs config says when it is None
+pub fn write_tree(
+    store: &dyn ObjectStore,
+    path: PathBuf,
+    jobs: Option<usize>,
+) -> Result<ObjectId, std::io::Error> {
+    // the cache can only hand out ids of objects in the repo's own store
+    let cache = if store.is_repo() {
+        StatCache::load()?
+    } else {
+        StatCache::empty()
+    };
+    let pool = thread_pool(jobs)?;
+    let (hash, _) = pool.install(|| write_dir(store, path, &[], Ignore::new(), &cache))?;
+    if store.is_repo() {
+        cache.save()?;
+    }
+    return Ok(hash);
+}
 
-    let ignore_path = path.join(".yeetignore");
-    let mut ignore_entries: Vec<String> = vec![String::from(".yeet")];
+fn thread_pool(jobs: Option<usize>) -> Result<rayon::ThreadPool, std::io::Error> {
+    let jobs = match jobs {
+        Some(x) => x,
+        None => data::get_jobs()?,
+    };
+    return rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(std::io::Error::other);
+}
 
-    if fs::try_exists(ignore_path.to_owned()).expect("cant read files") {
-        let mut other_entries = fs::read_to_string(ignore_path)
-            .unwrap()
-            .split('\n')
-            .map(|x| String::from(x))
-            .collect::<Vec<String>>();
-        ignore_entries.append(&mut other_entries);
-    }
+// rel is the path of the directory relative to the root passed to write_tree, ignore holds the
+// patterns of the ignore files in the directories above it.
+// returns the tree id and whether it came from the cache
+fn write_dir(
+    store: &dyn ObjectStore,
+    path: PathBuf,
+    rel: &[u8],
+    mut ignore: Ignore,
+    cache: &StatCache,
+) -> Result<(ObjectId, bool), std::io::Error> {
+    let dir_entries = fs::read_dir(&path)?;
+    ignore.load(&path, rel)?;
 
+    let mut entries = vec![];
     for i in dir_entries {
-        let entry = i.expect("Failed to read entry");
-        let filename = entry.file_name().to_str().unwrap().to_string();
-
-        if ignore_entries.contains(&filename) {
+        let entry = i?;
+        if entry.file_name() == ".yeet" {
             continue;
         }
 
-        let file_metadata = entry.metadata().expect("Failed to read metadata");
+        // does not follow symlinks
+        let file_metadata = entry.metadata()?;
+        let entry_rel = ignore::join(rel, &entry.file_name());
+        if ignore.is_ignored(&entry_rel, file_metadata.is_dir()) {
+            continue;
+        }
+        entries.push((entry, file_metadata, entry_rel));
+    }
 
-        if file_metadata.is_dir() {
-            let hash = write_tree(entry.path())?;
-            let d = FileData {
-                file_name: filename,
-                file_type: "tree".to_string(),
-                hash,
+    // entries are sorted when the tree is serialized so the order they finish in does not
+    // change the id
+    let written = entries
+        .into_par_iter()
+        .map(|(entry, file_metadata, entry_rel)| {
+            let filename = entry.file_name();
+            if file_metadata.is_dir() {
+                let (hash, cached) =
+                    write_dir(store, entry.path(), &entry_rel, ignore.clone(), cache)?;
+                let d = FileData {
+                    file_name: filename,
+                    mode: FileMode::Directory,
+                    file_type: ObjType::Tree,
+                    hash,
+                };
+                return Ok((d, cached));
+            }
+            let mode = entry_mode(&file_metadata);
+            let stat = Stat::from_metadata(&file_metadata);
+            let (cached, file_type, hash) = match cache.file(&entry_rel, mode, &stat) {
+                Some((file_type, hash)) => (true, file_type, hash),
+                None => {
+                    let (_, file_type, hash) = hash_entry(store, entry.path(), &file_metadata)?;
+                    cache.insert(entry_rel, mode, file_type, hash.clone(), stat);
+                    (false, file_type, hash)
+                }
             };
-            cur_dir_data.push(d)
-        } else {
-            let hash = hash_file(entry.path(), false)?;
             let d = FileData {
                 file_name: filename,
-                file_type: "blob".to_string(),
+                mode,
+                file_type,
                 hash,
             };
-            cur_dir_data.push(d);
+            return Ok((d, cached));
+        })
+        .collect::<Result<Vec<(FileData, bool)>, std::io::Error>>()?;
+
+    let unchanged = written.iter().all(|x| x.1);
+    let cur_dir_data = written.into_iter().map(|x| x.0).collect::<Vec<FileData>>();
+    if unchanged {
+        if let Some(hash) = cache.tree(rel, cur_dir_data.len()) {
+            return Ok((hash, true));
         }
     }
+    let hash = data::hash_dir(store, &cur_dir_data)?;
+    let stat = Stat::default();
+    cache.insert(
+        rel.to_vec(),
+        FileMode::Directory,
+        ObjType::Tree,
+        hash.clone(),
+        stat,
+    );
+    return Ok((hash, false));
+}
 
-    return data::hash_dir(&cur_dir_data);
+// mode a file or symlink is stored with, metadata must not follow symlinks
+fn entry_mode(metadata: &fs::Metadata) -> FileMode {
+    if metadata.is_symlink() {
+        return FileMode::Symlink;
+    } else if data::is_executable(metadata) {
+        return FileMode::Executable;
+    }
+    return FileMode::Regular;
 }
 
-pub fn read_tree(hash: String, write_dir: PathBuf) {
-    let write_dir_name = format!("{:?}", write_dir.as_os_str());
-    let root_dir = data::gen_tree(hash, write_dir_name, write_dir.to_owned());
+// stores a file or symlink, metadata must not follow symlinks
+fn hash_entry(
+    store: &dyn ObjectStore,
+    path: PathBuf,
+    metadata: &fs::Metadata,
+) -> Result<(FileMode, ObjType, ObjectId), std::io::Error> {
+    let mode = entry_mode(metadata);
+    if mode == FileMode::Symlink {
+        let target = data::read_link_bytes(&path)?;
+        let hash = data::write_obj_hash(store, &target, ObjType::Blob)?;
+        return Ok((mode, ObjType::Blob, hash));
+    }
+    let (file_type, hash) = hash_file(store, path, false)?;
+    return Ok((mode, file_type, hash));
+}
 
-    if let Err(e) = root_dir {
-        println!("Error: {}", e.to_string());
+// checks out a tree without moving HEAD
+pub fn read_tree(store: &dyn ObjectStore, hash: String, mode: CheckoutMode) {
+    let res = data::get_actual_hash(store, &hash).and_then(|x| update_worktree(store, &x, mode));
+    if let Err(e) = res {
+        eprintln!("Error: {}", e);
         exit(1);
     }
+}
+
+// how a checkout treats local changes, which are differences between HEAD's tree, the index
+// and the working tree. local changes to files the checkout does not change are kept unless
+// forced
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CheckoutMode {
+    // refuses when the checkout changes a file with local changes or an untracked file is in
+    // the way
+    Safe,
+    // like Safe but merges local changes into files the checkout changes too, as long as they
+    // do not touch the same lines
+    Carry,
+    // discards local changes and overwrites untracked files in the way
+    Force,
+}
+
+// what the working tree holds at a path
+#[derive(Debug, PartialEq)]
+enum WorkFile {
+    Missing,
+    Dir,
+    File(FileMode, ObjectId),
+}
+
+impl WorkFile {
+    fn matches(&self, entry: Option<&IndexEntry>) -> bool {
+        match (self, entry) {
+            (WorkFile::Missing, None) => true,
+            (WorkFile::File(mode, id), Some(x)) => *mode == x.mode && *id == x.id,
+            _ => false,
+        }
+    }
+}
+
+// a file whose stat matches its index entry is taken to be unchanged, others are hashed
+// without storing them
+fn work_file(rel: &[u8], entry: Option<&IndexEntry>) -> Result<WorkFile, std::io::Error> {
+    let path = rel_path(rel);
+    let metadata = match fs::symlink_metadata(&path) {
+        Ok(x) => x,
+        Err(e)
+            if e.kind() == std::io::ErrorKind::NotFound
+                || e.kind() == std::io::ErrorKind::NotADirectory =>
+        {
+            return Ok(WorkFile::Missing)
+        }
+        Err(e) => return Err(e),
+    };
+    if metadata.is_dir() {
+        return Ok(WorkFile::Dir);
+    }
+    let mode = entry_mode(&metadata);
+    if let Some(x) = entry {
+        if x.mode == mode && x.stat.matches(&Stat::from_metadata(&metadata)) {
+            return Ok(WorkFile::File(mode, x.id.clone()));
+        }
+    }
+    let (_, _, id) = hash_entry(&NullStore, path, &metadata)?;
+    return Ok(WorkFile::File(mode, id));
+}
+
+fn same_entry(a: Option<&IndexEntry>, b: Option<&IndexEntry>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.mode == b.mode && a.id == b.id,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// an untracked file where a directory above rel has to be created
+fn blocking_file(head: &Index, index: &Index, rel: &[u8]) -> Option<Vec<u8>> {
+    for (i, x) in rel.iter().enumerate() {
+        let dir = &rel[..i];
+        if *x != b'/' || head.get(dir).is_some() || index.get(dir).is_some() {
+            continue;
+        }
+        if fs::symlink_metadata(rel_path(dir)).is_ok_and(|x| !x.is_dir()) {
+            return Some(dir.to_vec());
+        }
+    }
+    return None;
+}
+
+// whether the directory at rel holds files that must not be deleted to write a file in its
+// place. files tracked in head or the index can be, except the carried ones
+fn has_untracked(
+    head: &Index,
+    index: &Index,
+    carried: &HashSet<Vec<u8>>,
+    rel: &[u8],
+) -> Result<bool, std::io::Error> {
+    for i in fs::read_dir(rel_path(rel))? {
+        let entry = i?;
+        let entry_rel = ignore::join(rel, &entry.file_name());
+        let untracked = if entry.file_type()?.is_dir() {
+            has_untracked(head, index, carried, &entry_rel)?
+        } else {
+            let tracked = head.get(&entry_rel).is_some() || index.get(&entry_rel).is_some();
+            !tracked || carried.contains(&entry_rel)
+        };
+        if untracked {
+            return Ok(true);
+        }
+    }
+    return Ok(false);
+}
+
+// merges the local changes to a file into the target's version of it, None if they can not
+// be merged
+fn merge_file(
+    store: &dyn ObjectStore,
+    rel: &[u8],
+    head: &IndexEntry,
+    work_mode: FileMode,
+    target: &IndexEntry,
+) -> Result<Option<(FileMode, Vec<u8>)>, std::io::Error> {
+    let modes = [head.mode, work_mode, target.mode];
+    if modes.contains(&FileMode::Symlink) {
+        return Ok(None);
+    }
+    let mode = if work_mode == head.mode {
+        target.mode
+    } else if target.mode == head.mode || target.mode == work_mode {
+        work_mode
+    } else {
+        return Ok(None);
+    };
+    let mut base: Vec<u8> = vec![];
+    data::copy_file(store, &head.id, &mut base)?;
+    let mut theirs: Vec<u8> = vec![];
+    data::copy_file(store, &target.id, &mut theirs)?;
+    let ours = fs::read(rel_path(rel))?;
+    return Ok(merge::merge(&base, &ours, &theirs).map(|x| (mode, x)));
+}
+
+// makes the working tree and the index match tree. only files that differ from it are written
+// or deleted, untracked and ignored files are left alone
+fn update_worktree(
+    store: &dyn ObjectStore,
+    tree: &ObjectId,
+    mode: CheckoutMode,
+) -> Result<(), std::io::Error> {
+    let head = Index::from_head(store)?;
+    let index = Index::load(store)?;
+    let mut target = Index::from_tree(store, tree)?;
+    let paths = head
+        .entries()
+        .chain(index.entries())
+        .chain(target.entries())
+        .map(|x| x.path.clone())
+        .collect::<BTreeSet<Vec<u8>>>();
+
+    let mut work: HashMap<Vec<u8>, WorkFile> = HashMap::new();
+    let mut carried: HashSet<Vec<u8>> = HashSet::new();
+    let mut merged: HashMap<Vec<u8>, (FileMode, Vec<u8>)> = HashMap::new();
+    let mut conflicts: BTreeSet<Vec<u8>> = BTreeSet::new();
+    let mut dirs: Vec<Vec<u8>> = vec![];
+    for path in &paths {
+        let (h, i, t) = (head.get(path), index.get(path), target.get(path));
+        let w = work_file(path, i)?;
+        if mode == CheckoutMode::Force {
+            // local changes and untracked files in the way are overwritten
+        } else if h.is_none() && i.is_none() {
+            // directories are checked once it is known which files in them are carried
+            if w == WorkFile::Dir {
+                dirs.push(path.clone());
+            } else if w != WorkFile::Missing && !w.matches(t) {
+                conflicts.insert(path.clone());
+            }
+            if let Some(x) = blocking_file(&head, &index, path) {
+                conflicts.insert(x);
+            }
+        } else if !same_entry(i, h) || !w.matches(i) {
+            // changes to files the checkout leaves alone or that already match the target are
+            // not lost
+            if same_entry(h, t) {
+                carried.insert(path.clone());
+            } else if !(same_entry(i, t) && w.matches(t)) {
+                let merge = match (h, &w, t) {
+                    (Some(h), WorkFile::File(work_mode, _), Some(t))
+                        if mode == CheckoutMode::Carry =>
+                    {
+                        merge_file(store, path, h, *work_mode, t)?
+                    }
+                    _ => None,
+                };
+                match merge {
+                    Some(x) => _ = merged.insert(path.clone(), x),
+                    None => _ = conflicts.insert(path.clone()),
+                }
+            }
+        }
+        work.insert(path.clone(), w);
+    }
+    for path in dirs {
+        if has_untracked(&head, &index, &carried, &path)? {
+            conflicts.insert(path);
+        }
+    }
+    if !conflicts.is_empty() {
+        let list = conflicts
+            .iter()
+            .map(|x| format!("\t{}", String::from_utf8_lossy(x)))
+            .collect::<Vec<String>>()
+            .join("\n");
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Checkout would overwrite local changes to:\n{}\nCommit them first, use \
+                 --carry to merge changes to lines the checkout does not touch or --force to \
+                 discard them",
+                list
+            ),
+        ));
+    }
 
-    if fs::try_exists(write_dir.to_owned()).expect("Unable to read dir") {
-        fs::remove_dir_all(write_dir.to_owned()).expect("Unable to remove previous revision");
+    // deleting first makes room for files that replace a directory or the other way around
+    for path in &paths {
+        if carried.contains(path) || target.get(path).is_some() {
+            continue;
+        }
+        if head.get(path).is_some() || index.get(path).is_some() {
+            remove_tracked(&rel_path(path))?;
+        }
     }
 
-    fs::create_dir_all(write_dir.to_owned()).expect("Unable to make dir");
-    data::write_entry(root_dir.unwrap());
+    let mut updated: Vec<IndexEntry> = vec![];
+    for x in target.entries() {
+        if carried.contains(&x.path) {
+            continue;
+        }
+        let file = rel_path(&x.path);
+        let mut entry = x.clone();
+        if let Some((mode, bytes)) = merged.get(&x.path) {
+            // the merge result is kept in the store too, until gc prunes it
+            let id = data::write_obj_hash(store, bytes, ObjType::Blob)?;
+            data::write_blob(store, *mode, &id, &file)?;
+            // left empty so the merged file shows up as changed
+            entry.stat = Stat::default();
+            updated.push(entry);
+            continue;
+        }
+        if !work[&x.path].matches(Some(x)) {
+            if let Some(blocking) = blocking_file(&head, &index, &x.path) {
+                fs::remove_file(rel_path(&blocking))?;
+            }
+            // the tracked files were deleted above, so unless forced only empty directories
+            // can be left
+            if fs::symlink_metadata(&file).is_ok_and(|x| x.is_dir())
+                && (mode == CheckoutMode::Force
+                    || !has_untracked(&head, &index, &carried, &x.path)?)
+            {
+                fs::remove_dir_all(&file)?;
+            }
+            data::write_blob(store, x.mode, &x.id, &file)?;
+        }
+        entry.stat = Stat::from_metadata(&fs::symlink_metadata(&file)?);
+        updated.push(entry);
+    }
+    for x in updated {
+        target.add(x);
+    }
+    for path in carried {
+        match index.get(&path) {
+            Some(x) => target.add(x.clone()),
+            None => _ = target.remove(&path),
+        }
+    }
+    return target.save();
+}
+
+// deletes a tracked file along with the directories it leaves empty
+fn remove_tracked(file: &Path) -> Result<(), std::io::Error> {
+    // a directory in place of the file is left for the files that go in it, only forced
+    // checkouts get here
+    if fs::symlink_metadata(file).is_ok_and(|x| x.is_dir()) {
+        return Ok(());
+    }
+    match fs::remove_file(file) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    // fails once a directory still has other files in it
+    for dir in file.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
 }
 
 pub fn set_author(name: String) {
     let mut auth_file =
         fs::File::create(PathBuf::from("./.yeet/repo_data/author")).expect("Unable to find data");
     auth_file
-        .write(name.as_bytes())
+        .write_all(name.as_bytes())
         .expect("Unable to set auth name: ");
     println!("Set author name to {}", name);
 }
 
-pub fn commit(message: String) -> Result<(), std::io::Error> {
+pub fn config(key: String, value: Option<String>) {
+    if !data::CONFIG_KEYS.contains(&key.as_str()) {
+        eprintln!(
+            "Error: Unknown config key: {}. Expected one of {:?}",
+            key,
+            data::CONFIG_KEYS
+        );
+        return;
+    }
+    match value {
+        Some(value) => match data::set_config(&key, &value) {
+            Ok(_) => println!("Set {} to {}", key, value),
+            Err(e) => eprintln!("Error: {}", e),
+        },
+        None => match data::get_repo_data(&key) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => println!("{} is not set", key),
+            Err(e) => eprintln!("Error: {}", e),
+        },
+    }
+}
+
+pub fn commit(
+    store: &dyn ObjectStore,
+    message: String,
+    abbrev: Option<usize>,
+) -> Result<(), std::io::Error> {
     let author = fs::read_to_string(PathBuf::from("./.yeet/repo_data/author"));
-    if let Err(_) = author {
+    if author.is_err() {
         return Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
             "Author name not found",
         ));
     }
     let time = OffsetDateTime::now_utc();
-    let mut parent = data::get_ref(&"HEAD".to_string(), PathBuf::new()).unwrap();
-    if parent == "" {
-        parent = "initial".to_string();
-    }
-    let id = write_tree(PathBuf::from("."))?;
-    let commit_data = format!(
-        "tree {}\nparent {}\nauthor {}\ntime {:?}\n{}",
-        id,
+    let head = data::get_ref(&"HEAD".to_string(), PathBuf::new()).unwrap();
+    // HEAD holds "initial" until the first commit
+    let parent = ObjectId::parse(&head);
+    let tree = Index::load(store)?.write_tree(store)?;
+    let commit_data = CommitData {
+        tree,
         parent,
-        author.unwrap(),
-        time,
-        message
-    );
-    let commit_id = data::write_obj_hash(commit_data.as_bytes(), "commit".to_string())?;
+        author: author.unwrap(),
+        time: format!("{:?}", time),
+        message: message.clone(),
+    };
+    let commit_id = data::write_object(store, &Object::Commit(commit_data))?;
 
-    data::set_ref("HEAD".to_string(), commit_id.to_string(), PathBuf::new()).unwrap();
-    println!("commit id: {}", commit_id);
+    data::set_ref(
+        store,
+        "HEAD".to_string(),
+        commit_id.to_string(),
+        PathBuf::new(),
+    )
+    .unwrap();
+    println!(
+        "commit id: {}",
+        data::abbreviate(store, &commit_id, abbrev)?
+    );
     println!("{}", message);
 
     Ok(())
 }
 
-pub fn log(commit_id: String) {
-    let res = data::log(commit_id);
+pub fn log(store: &dyn ObjectStore, commit_id: String, abbrev: Option<usize>) {
+    let res = data::log(store, commit_id, abbrev);
     if let Err(e) = res {
         eprintln!("Error: {}", e);
     }
 }
 
-pub fn checkout(commit_id: String) {
-    let tree_hash = data::get_commit_tree(&commit_id);
-    if let Err(e) = tree_hash {
-        eprintln!("Error: {}", e.to_string());
-    } else {
-        data::set_ref("HEAD".to_string(), commit_id, PathBuf::new()).expect("Failed to set head");
-        read_tree(tree_hash.unwrap(), PathBuf::from("./restored"));
+pub fn checkout(store: &dyn ObjectStore, commit_id: String, mode: CheckoutMode) {
+    let res = data::get_commit_tree(store, &commit_id)
+        .and_then(|x| update_worktree(store, &x, mode))
+        .and_then(|_| data::set_ref(store, "HEAD".to_string(), commit_id, PathBuf::new()));
+    if let Err(e) = res {
+        eprintln!("Error: {}", e);
+        exit(1);
     }
 }
 
-pub fn tag_commit(tag: String, hash: String) {
+pub fn tag_commit(store: &dyn ObjectStore, tag: String, hash: String) {
     println!("tag {} hash {}", tag, hash);
-    if let Ok(_) = tag.parse::<u64>() {
-        eprintln!("Error: Cannot use integer as tag name: {}", tag);
+    if ObjectId::parse(&tag).is_some() {
+        eprintln!("Error: Cannot use object id as tag name: {}", tag);
         return;
     }
-    let res = data::set_ref(tag, hash, PathBuf::from("tags"));
+    let res = data::set_ref(store, tag, hash, PathBuf::from("tags"));
     if let Err(e) = res {
         eprintln!("Error: {}", e);
     }
 }
 
-pub fn k() {
-    if let Err(e) = data::print_all_refs() {
+pub fn pack(store: &dyn ObjectStore) {
+    // unreachable packed objects are kept as loose objects so gc can still prune them by age
+    let repacked = store
+        .iter()
+        .and_then(|x| store.repack(&[], &x.collect::<Vec<ObjectId>>()));
+    match repacked {
+        Ok((count, deltas)) => println!("Packed {} objects ({} deltas)", count, deltas),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+// checks every object and ref, exits with 1 if anything is corrupt, missing or broken
+pub fn fsck(store: &dyn ObjectStore) {
+    let report = match fsck_objects(store) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+    for x in report.problems.iter().chain(&report.dangling) {
+        println!("{}", x);
+    }
+    if !report.problems.is_empty() {
+        println!("{} problems found", report.problems.len());
+        exit(1);
+    }
+}
+
+// what fsck found, one line each. dangling objects are not problems
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub problems: Vec<String>,
+    pub dangling: Vec<String>,
+}
+
+pub fn fsck_objects(store: &dyn ObjectStore) -> Result<FsckReport, std::io::Error> {
+    let ids = store.iter()?.collect::<Vec<ObjectId>>();
+    let existing = ids.iter().cloned().collect::<HashSet<ObjectId>>();
+    let mut referenced: HashSet<ObjectId> = HashSet::new();
+    let mut types: HashMap<ObjectId, ObjType> = HashMap::new();
+    let mut report = FsckReport::default();
+
+    let mut check_ref = |from: &str, type_: ObjType, id: &ObjectId, problems: &mut Vec<String>| {
+        referenced.insert(id.clone());
+        if !existing.contains(id) {
+            problems.push(format!("missing {} {} (referenced by {})", type_, id, from));
+        }
+    };
+
+    for id in &ids {
+        let bytes = match store.read(id) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                report.problems.push(format!("corrupt {}: {}", id, e));
+                continue;
+            }
+        };
+        if ObjectId::hash(&bytes) != *id {
+            report
+                .problems
+                .push(format!("corrupt {}: contents do not match id", id));
+            continue;
+        }
+        let obj = match data::read_object(store, id) {
+            Ok(obj) => obj,
+            Err(e) => {
+                report.problems.push(format!("corrupt {}: {}", id, e));
+                continue;
+            }
+        };
+        let type_ = obj.type_();
+        let from = format!("{} {}", type_, id);
+        let problems = &mut report.problems;
+        match obj {
+            Object::Blob(_) => {}
+            Object::Tree(entries) => {
+                for x in entries {
+                    check_ref(&from, x.file_type, &x.hash, problems);
+                }
+            }
+            Object::Commit(c) => {
+                check_ref(&from, ObjType::Tree, &c.tree, problems);
+                if let Some(parent) = c.parent {
+                    check_ref(&from, ObjType::Commit, &parent, problems);
+                }
+            }
+            Object::Tag(t) => check_ref(&from, t.type_, &t.object, problems),
+            Object::Chunks(chunks) => {
+                for x in chunks {
+                    check_ref(&from, ObjType::Blob, &x, problems);
+                }
+            }
+        }
+        types.insert(id.clone(), type_);
+    }
+
+    for yeet_ref in data::get_all_refs()? {
+        // HEAD holds "initial" until the first commit
+        if yeet_ref.ref_data == "initial" {
+            continue;
+        }
+        let id = match ObjectId::parse(&yeet_ref.ref_data) {
+            Some(id) => id,
+            None => {
+                report.problems.push(format!(
+                    "broken ref {}: invalid object id {}",
+                    yeet_ref.ref_name, yeet_ref.ref_data
+                ));
+                continue;
+            }
+        };
+        check_ref(
+            &format!("ref {}", yeet_ref.ref_name),
+            ObjType::Commit,
+            &id,
+            &mut report.problems,
+        );
+        if let Some(type_) = types.get(&id) {
+            if *type_ != ObjType::Commit {
+                report.problems.push(format!(
+                    "broken ref {}: points to {} {}",
+                    yeet_ref.ref_name, type_, id
+                ));
+            }
+        }
+    }
+
+    for id in &ids {
+        if let Some(type_) = types.get(id) {
+            if !referenced.contains(id) {
+                report.dangling.push(format!("dangling {} {}", type_, id));
+            }
+        }
+    }
+    return Ok(report);
+}
+
+// removes unreachable objects older than prune (None keeps them all) and packs the rest.
+// with dry_run only lists what would be removed
+pub fn gc(store: &dyn ObjectStore, prune: Option<Duration>, dry_run: bool) {
+    if let Err(e) = gc_objects(store, prune, dry_run) {
+        eprintln!("Error: {}", e);
+    }
+}
+
+fn gc_objects(
+    store: &dyn ObjectStore,
+    prune: Option<Duration>,
+    dry_run: bool,
+) -> Result<(), std::io::Error> {
+    // yeet has no reflog yet so refs and the index are the only roots
+    let reachable = data::reachable_objects(store)?
+        .into_iter()
+        .map(|x| x.id)
+        .collect::<HashSet<ObjectId>>();
+    // staged files are not reachable from any commit yet, they are packed along with the
+    // reachable objects
+    let mut staged: HashSet<ObjectId> = HashSet::new();
+    for x in Index::load(store)?.entries() {
+        if x.type_ == ObjType::Chunks {
+            if let Object::Chunks(ids) = data::read_object(store, &x.id)? {
+                staged.extend(ids);
+            }
+        }
+        staged.insert(x.id.clone());
+    }
+    // nothing is older than a prune age reaching back before the epoch
+    let cutoff = prune.and_then(|x| SystemTime::now().checked_sub(x));
+    let expired = |mtime: SystemTime| cutoff.is_some_and(|x| mtime <= x);
+    let action = if dry_run { "would remove" } else { "removing" };
+    let mut removed = 0;
+
+    // unreachable packed objects that are too young to prune are unpacked by repack, it gives
+    // them the age of their pack
+    let mut young: Vec<ObjectId> = vec![];
+    for id in store.iter()?.collect::<Vec<ObjectId>>() {
+        if reachable.contains(&id) || staged.contains(&id) {
+            continue;
+        }
+        if expired(store.modified(&id)?) {
+            println!("{} {}", action, id);
+            removed += 1;
+            if !dry_run {
+                store.remove(&id)?;
+            }
+        } else {
+            young.push(id);
+        }
+    }
+
+    if dry_run {
+        println!("{} unreachable objects would be removed", removed);
+        return Ok(());
+    }
+    // the stat cache may point at objects that were just removed
+    if removed > 0 && store.is_repo() {
+        match fs::remove_file(cache::CACHE_PATH) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    let staged = staged.into_iter().collect::<Vec<ObjectId>>();
+    let (count, deltas) = store.repack(&staged, &young)?;
+    println!(
+        "Removed {} unreachable objects, packed {} objects ({} deltas)",
+        removed, count, deltas
+    );
+    Ok(())
+}
+
+// stages paths, directories are added recursively without their ignored files and files
+// deleted from the working tree are removed from the index.
+// changed files are hashed on jobs threads, see write_tree
+pub fn add(store: &dyn ObjectStore, paths: &[String], jobs: Option<usize>) {
+    let mut index = match Index::load(store) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+    let pool = match thread_pool(jobs) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+    let mut failed = false;
+    let mut pending: Vec<Pending> = vec![];
+    for path in paths {
+        let res = match repo_relative(path) {
+            Some(rel) => add_path(&mut index, &rel, &mut pending),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is outside the repository", path),
+            )),
+        };
+        if let Err(e) = res {
+            eprintln!("Error: {}", e);
+            failed = true;
+        }
+    }
+
+    let hashed = pool.install(|| {
+        pending
+            .into_par_iter()
+            .map(|x| {
+                let (mode, type_, id) = hash_entry(store, x.path, &x.metadata)?;
+                return Ok(IndexEntry {
+                    path: x.rel,
+                    mode,
+                    type_,
+                    id,
+                    stat: Stat::from_metadata(&x.metadata),
+                });
+            })
+            .collect::<Vec<Result<IndexEntry, std::io::Error>>>()
+    });
+    for x in hashed {
+        match x {
+            Ok(entry) => index.add(entry),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                failed = true;
+            }
+        }
+    }
+    if let Err(e) = index.save() {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+// working tree path of a path relative to the repo root
+fn rel_path(rel: &[u8]) -> PathBuf {
+    if rel.is_empty() {
+        return PathBuf::from(".");
+    }
+    return PathBuf::from(object::name_from_bytes(rel));
+}
+
+// a changed file found by add, they are all hashed together once the paths have been walked
+struct Pending {
+    path: PathBuf,
+    rel: Vec<u8>,
+    metadata: fs::Metadata,
+}
+
+fn add_path(
+    index: &mut Index,
+    rel: &[u8],
+    pending: &mut Vec<Pending>,
+) -> Result<(), std::io::Error> {
+    let path = rel_path(rel);
+    if rel.split(|x| *x == b'/').any(|x| x == b".yeet") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Cannot add {:?}, it is inside .yeet", path),
+        ));
+    }
+    let metadata = match fs::symlink_metadata(&path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let tracked = index.paths_under(rel);
+            if tracked.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Path {:?} did not match any files", path),
+                ));
+            }
+            for i in tracked {
+                index.remove(&i);
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    if !rel.is_empty() {
+        if let Some(p) = ignore::check(&PathBuf::new(), rel, metadata.is_dir())? {
+            if !p.negated {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "{:?} is ignored by {}:{}:{}",
+                        path,
+                        p.source.display(),
+                        p.line,
+                        p.text
+                    ),
+                ));
+            }
+        }
+    }
+    if !metadata.is_dir() {
+        add_file(index, path, rel.to_vec(), metadata, pending);
+        return Ok(());
+    }
+
+    let mut ignore = ignore::load_parents(&PathBuf::new(), rel)?;
+    add_dir(index, path, rel, &mut ignore, pending)?;
+    for i in index.paths_under(rel) {
+        if fs::symlink_metadata(rel_path(&i)).is_err() {
+            index.remove(&i);
+        }
+    }
+    Ok(())
+}
+
+// ignored files are skipped unless they are already tracked
+fn add_dir(
+    index: &mut Index,
+    path: PathBuf,
+    rel: &[u8],
+    ignore: &mut Ignore,
+    pending: &mut Vec<Pending>,
+) -> Result<(), std::io::Error> {
+    let inherited = ignore.len();
+    ignore.load(&path, rel)?;
+    for i in fs::read_dir(&path)? {
+        let entry = i?;
+        let filename = entry.file_name();
+        if filename == ".yeet" {
+            continue;
+        }
+        // does not follow symlinks
+        let metadata = entry.metadata()?;
+        let entry_rel = ignore::join(rel, &filename);
+        if ignore.is_ignored(&entry_rel, metadata.is_dir())
+            && index.paths_under(&entry_rel).is_empty()
+        {
+            continue;
+        }
+        if metadata.is_dir() {
+            add_dir(index, entry.path(), &entry_rel, ignore, pending)?;
+        } else {
+            add_file(index, entry.path(), entry_rel, metadata, pending);
+        }
+    }
+    ignore.truncate(inherited);
+    Ok(())
+}
+
+fn add_file(
+    index: &Index,
+    path: PathBuf,
+    rel: Vec<u8>,
+    metadata: fs::Metadata,
+    pending: &mut Vec<Pending>,
+) {
+    // the index doubles as a stat cache for files that did not change since they were added
+    if let Some(x) = index.get(&rel) {
+        if x.mode == entry_mode(&metadata) && x.stat.matches(&Stat::from_metadata(&metadata)) {
+            return;
+        }
+    }
+    pending.push(Pending {
+        path,
+        rel,
+        metadata,
+    });
+}
+
+// writes paths as they are in the tree of the source commit into the working tree, files
+// that are not in the source and the index are left alone
+pub fn restore(store: &dyn ObjectStore, source: String, paths: &[String]) {
+    let tree = data::get_commit_tree(store, &source)
+        .and_then(|x| Ok((x, Index::from_head(store)?, Index::load(store)?)));
+    let (tree, head, index) = match tree {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+    let mut failed = false;
+    for path in paths {
+        let res = match repo_relative(path) {
+            Some(rel) => restore_path(store, &tree, &head, &index, &rel),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is outside the repository", path),
+            )),
+        };
+        if let Err(e) = res {
+            eprintln!("Error: {}", e);
+            failed = true;
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+fn restore_path(
+    store: &dyn ObjectStore,
+    tree: &ObjectId,
+    head: &Index,
+    index: &Index,
+    rel: &[u8],
+) -> Result<(), std::io::Error> {
+    // only the trees along rel are read, the rest of the source tree is never touched
+    let entry = if rel.is_empty() {
+        data::gen_tree(store, tree.to_string(), OsString::new(), PathBuf::new())?
+    } else {
+        let not_found = || {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Path {:?} is not in the source tree", rel_path(rel)),
+            )
+        };
+        let mut tree = tree.clone();
+        let mut names = rel.split(|x| *x == b'/').peekable();
+        loop {
+            let name = names.next().unwrap();
+            let data = data::decode_dir_data(store, &tree)?
+                .into_iter()
+                .find(|x| object::name_to_bytes(&x.file_name) == name)
+                .ok_or_else(not_found)?;
+            if names.peek().is_none() {
+                break data::gen_entry(store, &data, rel_path(rel))?;
+            }
+            if data.file_type != ObjType::Tree {
+                return Err(not_found());
+            }
+            tree = data.hash;
+        }
+    };
+    // files and directories where the entry goes are replaced when they hold only tracked
+    // files, all of them are checked before anything is deleted
+    let mut obstacles = vec![];
+    let parent = (0..rel.len())
+        .filter(|x| rel[*x] == b'/')
+        .map(|x| &rel[..x])
+        .find(|x| fs::symlink_metadata(rel_path(x)).is_ok_and(|x| !x.is_dir()));
+    match parent {
+        Some(x) => restore_obstacle(head, index, rel, x, false, &mut obstacles)?,
+        None => restore_obstacles(head, index, rel, &entry, rel, &mut obstacles)?,
+    }
+    for x in obstacles {
+        let path = rel_path(&x);
+        if fs::symlink_metadata(&path)?.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    return data::write_entry(store, &entry);
+}
+
+// the paths in the way of writing entry at rel, which is under the restored path
+fn restore_obstacles(
+    head: &Index,
+    index: &Index,
+    restored: &[u8],
+    entry: &DirEntry,
+    rel: &[u8],
+    obstacles: &mut Vec<Vec<u8>>,
+) -> Result<(), std::io::Error> {
+    let metadata = match fs::symlink_metadata(rel_path(rel)) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    match (entry.type_ == ObjType::Tree, metadata.is_dir()) {
+        (true, true) => {
+            for child in entry.children.iter().flatten() {
+                let child_rel = ignore::join(rel, &child.name);
+                restore_obstacles(head, index, restored, child, &child_rel, obstacles)?;
+            }
+        }
+        (true, false) => restore_obstacle(head, index, restored, rel, false, obstacles)?,
+        (false, true) => restore_obstacle(head, index, restored, rel, true, obstacles)?,
+        // files are overwritten
+        (false, false) => {}
+    }
+    return Ok(());
+}
+
+fn restore_obstacle(
+    head: &Index,
+    index: &Index,
+    restored: &[u8],
+    rel: &[u8],
+    is_dir: bool,
+    obstacles: &mut Vec<Vec<u8>>,
+) -> Result<(), std::io::Error> {
+    let untracked = if is_dir {
+        has_untracked(head, index, &HashSet::new(), rel)?
+    } else {
+        head.get(rel).is_none() && index.get(rel).is_none()
+    };
+    if untracked {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!(
+                "Untracked files at {:?} are in the way of restoring {:?}",
+                rel_path(rel),
+                rel_path(restored)
+            ),
+        ));
+    }
+    obstacles.push(rel.to_vec());
+    return Ok(());
+}
+
+// removes paths from the index and, unless cached, from the working tree along with
+// directories left empty
+pub fn rm(store: &dyn ObjectStore, paths: &[String], cached: bool) {
+    let mut index = match Index::load(store) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+    let mut failed = false;
+    for path in paths {
+        let tracked = match repo_relative(path) {
+            Some(rel) => index.paths_under(&rel),
+            None => vec![],
+        };
+        if tracked.is_empty() {
+            eprintln!("Error: {} did not match any tracked files", path);
+            failed = true;
+            continue;
+        }
+        for i in tracked {
+            index.remove(&i);
+            if cached {
+                continue;
+            }
+            if let Err(e) = remove_tracked(&rel_path(&i)) {
+                eprintln!("Error: {}", e);
+                failed = true;
+            }
+        }
+    }
+    if let Err(e) = index.save() {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+// prints the ignored paths, or with verbose every path along with the ignore file, line and
+// pattern that decided it. exits with 1 if none of the paths are ignored
+pub fn check_ignore(paths: &[String], verbose: bool) {
+    let mut ignored = 0;
+    for path in paths {
+        let rel = match repo_relative(path) {
+            Some(x) => x,
+            None => {
+                eprintln!("Error: {} is outside the repository", path);
+                exit(128);
+            }
+        };
+        let is_dir = path.ends_with('/') || fs::symlink_metadata(path).is_ok_and(|x| x.is_dir());
+        // the root itself is never ignored
+        let matched = match ignore::check(&PathBuf::new(), &rel, is_dir) {
+            _ if rel.is_empty() => None,
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit(128);
+            }
+        };
+        let is_ignored = matched.as_ref().is_some_and(|x| !x.negated);
+        if is_ignored {
+            ignored += 1;
+        }
+        match matched {
+            Some(p) if verbose => {
+                println!("{}:{}:{}\t{}", p.source.display(), p.line, p.text, path)
+            }
+            None if verbose => println!("::\t{}", path),
+            _ if is_ignored => println!("{}", path),
+            _ => {}
+        }
+    }
+    if ignored == 0 {
+        exit(1);
+    }
+}
+
+// path relative to the repo root (the current directory) with "/" between components,
+// empty for the root itself and None if it points outside of it
+fn repo_relative(path: &str) -> Option<Vec<u8>> {
+    let mut names: Vec<&OsStr> = vec![];
+    for i in Path::new(path).components() {
+        match i {
+            Component::Normal(x) => names.push(x),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                names.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    return Some(names.iter().fold(vec![], |rel, x| ignore::join(&rel, x)));
+}
+
+pub fn k(store: &dyn ObjectStore, abbrev: Option<usize>) {
+    if let Err(e) = data::print_all_refs(store, abbrev) {
         eprintln!("Error: {}", e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{FsStore, MemoryStore};
+    use crate::testutil::{write, TempRepo};
+
+    const WEEK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+    fn staged_id(store: &dyn ObjectStore, path: &str) -> ObjectId {
+        return Index::load(store)
+            .unwrap()
+            .get(path.as_bytes())
+            .unwrap()
+            .id
+            .clone();
+    }
+
+    fn commit_all(store: &dyn ObjectStore, message: &str) {
+        add(store, &[".".to_string()], Some(1));
+        commit(store, message.to_string(), None).unwrap();
+    }
+
+    fn head_id() -> ObjectId {
+        let head = data::get_ref(&"HEAD".to_string(), PathBuf::new()).unwrap();
+        return ObjectId::parse(&head).unwrap();
+    }
+
+    // checkout without exiting on errors
+    fn try_checkout(store: &dyn ObjectStore, commit: &ObjectId, mode: CheckoutMode) -> String {
+        let tree = data::get_commit_tree(store, &commit.to_string()).unwrap();
+        if let Err(e) = update_worktree(store, &tree, mode) {
+            return e.to_string();
+        }
+        data::set_ref(
+            store,
+            "HEAD".to_string(),
+            commit.to_string(),
+            PathBuf::new(),
+        )
+        .unwrap();
+        return String::new();
+    }
+
+    fn read(path: &str) -> String {
+        return fs::read_to_string(path).unwrap();
+    }
+
+    // first has keep and f, second changes f
+    fn two_commits(store: &dyn ObjectStore) -> (ObjectId, ObjectId) {
+        write("keep", "same in both\n");
+        write("f", "1\n");
+        commit_all(store, "first");
+        let first = head_id();
+        write("f", "2\n");
+        commit_all(store, "second");
+        return (first, head_id());
+    }
+
+    #[test]
+    fn checkout_refuses_to_overwrite_local_changes() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        let (first, second) = two_commits(&store);
+        write("f", "local\n");
+        write("keep", "also local\n");
+        for mode in [CheckoutMode::Safe, CheckoutMode::Carry] {
+            let error = try_checkout(&store, &first, mode);
+            assert!(error.contains("\n\tf\n"), "{}", error);
+            assert!(!error.contains("keep"), "{}", error);
+        }
+        assert_eq!(head_id(), second);
+        assert_eq!(read("f"), "local\n");
+
+        // staged changes count too
+        add(&store, &["f".to_string()], Some(1));
+        write("f", "2\n");
+        assert!(try_checkout(&store, &first, CheckoutMode::Safe).contains("\tf\n"));
+    }
+
+    #[test]
+    fn checkout_keeps_changes_to_files_it_does_not_change() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        let (first, second) = two_commits(&store);
+        write("keep", "local\n");
+        write("new", "staged\n");
+        add(&store, &["new".to_string()], Some(1));
+        let staged = staged_id(&store, "new");
+        assert_eq!(try_checkout(&store, &first, CheckoutMode::Safe), "");
+        assert_eq!(read("f"), "1\n");
+        assert_eq!(read("keep"), "local\n");
+        assert_eq!(staged_id(&store, "new"), staged);
+
+        // carry keeps the index entry of a staged edit
+        write("keep", "staged edit\n");
+        add(&store, &["keep".to_string()], Some(1));
+        let staged = staged_id(&store, "keep");
+        write("keep", "staged edit and more\n");
+        assert_eq!(try_checkout(&store, &second, CheckoutMode::Carry), "");
+        assert_eq!(read("f"), "2\n");
+        assert_eq!(read("keep"), "staged edit and more\n");
+        assert_eq!(staged_id(&store, "keep"), staged);
+    }
+
+    #[test]
+    fn checkout_carry_merges_changes_to_other_lines() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        write("m", "1\n2\n3\n4\n5\n6\n7\n");
+        commit_all(&store, "first");
+        let first = head_id();
+        write("m", "1\n2\n3\n4\n5\nsix\n7\n");
+        commit_all(&store, "second");
+
+        write("m", "1\ntwo\n3\n4\n5\nsix\n7\n");
+        assert!(try_checkout(&store, &first, CheckoutMode::Safe).contains("\tm\n"));
+        assert_eq!(try_checkout(&store, &first, CheckoutMode::Carry), "");
+        assert_eq!(read("m"), "1\ntwo\n3\n4\n5\n6\n7\n");
+        // the merged file shows up as changed against the new HEAD
+        let entry = Index::load(&store).unwrap().get(b"m").unwrap().clone();
+        assert_eq!(entry.stat, Stat::default());
+        assert_ne!(
+            work_file(b"m", Some(&entry)).unwrap(),
+            WorkFile::File(entry.mode, entry.id)
+        );
+
+        // changes to the same lines are not merged
+        commit_all(&store, "third");
+        let third = head_id();
+        write("m", "1\ntwo\n3\n4\n5\nSIX\n7\n");
+        commit_all(&store, "fourth");
+        write("m", "1\ntwo\n3\n4\n5\nsix?\n7\n");
+        assert!(try_checkout(&store, &third, CheckoutMode::Carry).contains("\tm\n"));
+        assert_eq!(read("m"), "1\ntwo\n3\n4\n5\nsix?\n7\n");
+    }
+
+    #[test]
+    fn checkout_force_discards_local_changes() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        let (first, _) = two_commits(&store);
+        write("f", "local\n");
+        write("keep", "local\n");
+        add(&store, &["keep".to_string()], Some(1));
+        assert_eq!(try_checkout(&store, &first, CheckoutMode::Force), "");
+        assert_eq!(read("f"), "1\n");
+        assert_eq!(read("keep"), "same in both\n");
+        assert_eq!(head_id(), first);
+    }
+
+    #[test]
+    fn checkout_refuses_untracked_files_in_the_way() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        write("a", "a\n");
+        commit_all(&store, "first");
+        let first = head_id();
+        write("new", "committed\n");
+        write("d/x", "x\n");
+        commit_all(&store, "second");
+        let second = head_id();
+        assert_eq!(try_checkout(&store, &first, CheckoutMode::Safe), "");
+        assert!(!Path::new("new").exists() && !Path::new("d").exists());
+
+        write("new", "untracked\n");
+        write("d", "untracked file where a directory goes\n");
+        let error = try_checkout(&store, &second, CheckoutMode::Safe);
+        assert!(error.contains("\td\n\tnew\n"), "{}", error);
+        assert_eq!(read("new"), "untracked\n");
+
+        // nothing is lost when the untracked file already matches
+        fs::remove_file("d").unwrap();
+        write("new", "committed\n");
+        assert_eq!(try_checkout(&store, &second, CheckoutMode::Safe), "");
+
+        assert_eq!(try_checkout(&store, &first, CheckoutMode::Safe), "");
+        write("new", "untracked\n");
+        write("d", "untracked\n");
+        assert_eq!(try_checkout(&store, &second, CheckoutMode::Force), "");
+        assert_eq!(read("new"), "committed\n");
+        assert_eq!(read("d/x"), "x\n");
+    }
+
+    #[test]
+    fn checkout_leaves_untracked_and_ignored_files_alone() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        write(".yeetignore", "*.log\n");
+        write("dir/x", "x\n");
+        commit_all(&store, "first");
+        let first = head_id();
+        fs::remove_dir_all("dir").unwrap();
+        write("other", "other\n");
+        commit_all(&store, "second");
+        let second = head_id();
+
+        write("debug.log", "ignored\n");
+        write("notes", "untracked\n");
+        assert_eq!(try_checkout(&store, &first, CheckoutMode::Safe), "");
+        write("dir/build.log", "ignored\n");
+        write("dir/sub/notes", "untracked\n");
+        for mode in [CheckoutMode::Safe, CheckoutMode::Force] {
+            assert_eq!(try_checkout(&store, &second, mode), "");
+            assert!(!Path::new("dir/x").exists());
+            assert_eq!(try_checkout(&store, &first, mode), "");
+        }
+        for path in ["debug.log", "notes", "dir/build.log", "dir/sub/notes"] {
+            assert!(Path::new(path).exists(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn checkout_swaps_directories_and_files() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        write("a/x", "in a directory\n");
+        write("a/sub/y", "deeper\n");
+        commit_all(&store, "directory");
+        let dir = head_id();
+        fs::remove_dir_all("a").unwrap();
+        write("a", "a file\n");
+        commit_all(&store, "file");
+        let file = head_id();
+
+        for _ in 0..2 {
+            assert_eq!(try_checkout(&store, &dir, CheckoutMode::Safe), "");
+            assert_eq!(read("a/x"), "in a directory\n");
+            assert_eq!(read("a/sub/y"), "deeper\n");
+            // empty directories are not in the way
+            fs::create_dir("a/empty").unwrap();
+            assert_eq!(try_checkout(&store, &file, CheckoutMode::Safe), "");
+            assert_eq!(read("a"), "a file\n");
+        }
+
+        // untracked files keep the directory from being replaced
+        assert_eq!(try_checkout(&store, &dir, CheckoutMode::Safe), "");
+        write("a/sub/untracked", "untracked\n");
+        let error = try_checkout(&store, &file, CheckoutMode::Safe);
+        assert!(error.contains("\ta\n"), "{}", error);
+        assert_eq!(read("a/x"), "in a directory\n");
+        // and so do staged files the checkout would not remove
+        fs::remove_file("a/sub/untracked").unwrap();
+        write("a/staged", "staged\n");
+        add(&store, &["a/staged".to_string()], Some(1));
+        assert!(try_checkout(&store, &file, CheckoutMode::Safe).contains("\ta\n"));
+        assert_eq!(try_checkout(&store, &file, CheckoutMode::Force), "");
+        assert_eq!(read("a"), "a file\n");
+        // a forced checkout replaces the directory that took the place of a file
+        fs::remove_file("a").unwrap();
+        write("a/x", "local\n");
+        assert_eq!(try_checkout(&store, &dir, CheckoutMode::Force), "");
+        assert_eq!(read("a/x"), "in a directory\n");
+        assert_eq!(read("a/sub/y"), "deeper\n");
+    }
+
+    // restore without exiting on errors
+    fn try_restore(store: &dyn ObjectStore, source: &ObjectId, path: &str) -> String {
+        let tree = data::get_commit_tree(store, &source.to_string()).unwrap();
+        let head = Index::from_head(store).unwrap();
+        let index = Index::load(store).unwrap();
+        let rel = repo_relative(path).unwrap();
+        return match restore_path(store, &tree, &head, &index, &rel) {
+            Ok(()) => String::new(),
+            Err(e) => e.to_string(),
+        };
+    }
+
+    #[test]
+    fn restore_swaps_directories_and_files() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        write("a/x", "in a directory\n");
+        write("a/sub/y", "deeper\n");
+        commit_all(&store, "directory");
+        let dir = head_id();
+        fs::remove_dir_all("a").unwrap();
+        write("a", "a file\n");
+        commit_all(&store, "file");
+        let file = head_id();
+
+        // a tracked file is replaced by the directory of a restored file or directory
+        assert_eq!(try_restore(&store, &dir, "a/x"), "");
+        assert_eq!(read("a/x"), "in a directory\n");
+        assert!(!Path::new("a/sub").exists());
+        assert_eq!(try_checkout(&store, &file, CheckoutMode::Force), "");
+        assert_eq!(try_restore(&store, &dir, "a"), "");
+        assert_eq!(read("a/x"), "in a directory\n");
+        assert_eq!(read("a/sub/y"), "deeper\n");
+
+        // a directory of tracked files is replaced by a restored file
+        assert_eq!(try_checkout(&store, &dir, CheckoutMode::Force), "");
+        write("a/sub/untracked", "untracked\n");
+        let error = try_restore(&store, &file, "a");
+        assert!(error.contains("in the way"), "{}", error);
+        assert_eq!(read("a/sub/untracked"), "untracked\n");
+        fs::remove_file("a/sub/untracked").unwrap();
+        fs::create_dir("a/empty").unwrap();
+        assert_eq!(try_restore(&store, &file, "a"), "");
+        assert_eq!(read("a"), "a file\n");
+
+        // the file is untracked in HEAD now
+        for path in ["a", "a/x"] {
+            let error = try_restore(&store, &dir, path);
+            assert!(error.contains("in the way"), "{}", error);
+            assert_eq!(read("a"), "a file\n");
+        }
+    }
+
+    #[test]
+    fn restore_reads_only_the_trees_along_the_path() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        write("ok/f", "fine\n");
+        write("broken/g", "lost\n");
+        commit_all(&store, "both");
+        let both = head_id();
+        let tree = data::get_commit_tree(&store, &both.to_string()).unwrap();
+        let broken = data::decode_dir_data(&store, &tree)
+            .unwrap()
+            .into_iter()
+            .find(|x| x.file_name == "broken")
+            .unwrap();
+        fs::remove_dir_all("broken").unwrap();
+        commit_all(&store, "without broken");
+        fs::remove_file(data::object_path(&broken.hash)).unwrap();
+
+        write("ok/f", "changed\n");
+        assert_eq!(try_restore(&store, &both, "ok/f"), "");
+        assert_eq!(read("ok/f"), "fine\n");
+        // the missing subtree is an error only when it is needed
+        assert_ne!(try_restore(&store, &both, "broken/g"), "");
+        assert_ne!(try_restore(&store, &both, "."), "");
+        assert!(!Path::new("broken").exists());
+    }
+
+    #[test]
+    fn write_tree_is_the_same_on_any_number_of_threads() {
+        let _repo = TempRepo::new();
+        for i in 0..40 {
+            write(format!("d{}/sub{}/f{}", i % 4, i % 3, i), &i.to_string());
+        }
+        write(".yeetignore", "*.tmp\n");
+        write("d1/x.tmp", "ignored");
+        let store = MemoryStore::new();
+        let tree = write_tree(&store, PathBuf::from("."), Some(1)).unwrap();
+        for jobs in [2, 8] {
+            assert_eq!(
+                write_tree(&store, PathBuf::from("."), Some(jobs)).unwrap(),
+                tree
+            );
+        }
+        add(&store, &[".".to_string()], Some(1));
+        assert_eq!(
+            Index::load(&store).unwrap().write_tree(&store).unwrap(),
+            tree
+        );
+        add(&store, &[".".to_string()], Some(8));
+        assert_eq!(
+            Index::load(&store).unwrap().write_tree(&store).unwrap(),
+            tree
+        );
+    }
+
+    #[test]
+    fn write_tree_returns_errors_from_the_walk() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        write("file", "not a directory\n");
+        assert!(write_tree(&store, PathBuf::from("file"), Some(2)).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            write("d/sub/x", "x\n");
+            fs::set_permissions("d/sub", fs::Permissions::from_mode(0o000)).unwrap();
+            // root can read it anyway
+            let unreadable = fs::read_dir("d/sub").is_err();
+            let res = write_tree(&store, PathBuf::from("."), Some(2));
+            fs::set_permissions("d/sub", fs::Permissions::from_mode(0o755)).unwrap();
+            assert_eq!(res.is_err(), unreadable);
+        }
+    }
+
+    #[test]
+    fn write_tree_cache_only_serves_the_repo_store() {
+        let _repo = TempRepo::new();
+        write("a", "a");
+        write("d/b", "b");
+        // files changed in the same second write_tree starts are not cached
+        let old = SystemTime::now() - Duration::from_secs(60);
+        for path in ["a", "d/b"] {
+            let file = fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(old).unwrap();
+        }
+        let tree = write_tree(&FsStore, PathBuf::from("."), Some(1)).unwrap();
+        let store = MemoryStore::new();
+        assert_eq!(
+            write_tree(&store, PathBuf::from("."), Some(1)).unwrap(),
+            tree
+        );
+        for x in Index::from_tree(&store, &tree).unwrap().entries() {
+            assert!(store.exists(&x.id).unwrap());
+        }
+
+        // adding and removing files changes the tree even when the directory is cached
+        write("d/c", "c");
+        let added = write_tree(&FsStore, PathBuf::from("."), Some(1)).unwrap();
+        assert_ne!(added, tree);
+        fs::remove_file("d/c").unwrap();
+        assert_eq!(
+            write_tree(&FsStore, PathBuf::from("."), Some(1)).unwrap(),
+            tree
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checkout_keeps_symlinks_and_executables() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        let mode = |path: &str| fs::symlink_metadata(path).unwrap().permissions().mode();
+        write("run.sh", "#!/bin/sh\n");
+        fs::set_permissions("run.sh", fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("run.sh", "link").unwrap();
+        commit_all(&store, "executable and symlink");
+        let special = head_id();
+        fs::remove_file("link").unwrap();
+        write("link", "run.sh");
+        fs::set_permissions("run.sh", fs::Permissions::from_mode(0o644)).unwrap();
+        commit_all(&store, "plain files");
+        let plain = head_id();
+
+        for _ in 0..2 {
+            assert_eq!(try_checkout(&store, &special, CheckoutMode::Force), "");
+            assert!(fs::symlink_metadata("link").unwrap().is_symlink());
+            assert_eq!(fs::read_link("link").unwrap(), PathBuf::from("run.sh"));
+            assert_eq!(mode("run.sh") & 0o777, 0o755);
+            assert_eq!(try_checkout(&store, &plain, CheckoutMode::Force), "");
+            assert!(fs::symlink_metadata("link").unwrap().is_file());
+            assert_eq!(read("link"), "run.sh");
+            assert_eq!(mode("run.sh") & 0o111, 0);
+        }
+    }
+
+    #[test]
+    fn fsck_reports_corrupt_and_missing_objects() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        write("f", "contents\n");
+        write("d/g", "more\n");
+        commit_all(&store, "first");
+        let report = fsck_objects(&store).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report);
+        assert!(report.dangling.is_empty(), "{:?}", report);
+
+        let blob = staged_id(&store, "f");
+        fs::write(data::object_path(&blob), "not zlib and not an object").unwrap();
+        let report = fsck_objects(&store).unwrap();
+        assert_eq!(report.problems.len(), 1, "{:?}", report);
+        assert!(report.problems[0].starts_with(&format!("corrupt {}", blob)));
+
+        let tree = data::get_commit_tree(&store, &"HEAD".to_string()).unwrap();
+        fs::remove_file(data::object_path(&tree)).unwrap();
+        let report = fsck_objects(&store).unwrap();
+        let missing = format!("missing tree {} (referenced by commit {})", tree, head_id());
+        assert!(report.problems.contains(&missing), "{:?}", report);
+        // the subtree is only referenced by the missing tree
+        assert_eq!(report.dangling.len(), 1, "{:?}", report);
+    }
+
+    #[test]
+    fn gc_memory_store() {
+        let _repo = TempRepo::new();
+        let store = MemoryStore::new();
+        write("a", "committed");
+        commit_all(&store, "first");
+        write("b", "staged");
+        add(&store, &["b".to_string()], Some(1));
+        let staged = staged_id(&store, "b");
+        let lost = data::write_obj_hash(&store, b"unreachable", ObjType::Blob).unwrap();
+
+        gc_objects(&store, Some(WEEK), false).unwrap();
+        assert!(store.exists(&lost).unwrap());
+        gc_objects(&store, Some(Duration::ZERO), true).unwrap();
+        assert!(store.exists(&lost).unwrap());
+        gc_objects(&store, Some(Duration::ZERO), false).unwrap();
+        assert!(!store.exists(&lost).unwrap());
+
+        assert!(store.exists(&staged).unwrap());
+        for x in data::reachable_objects(&store).unwrap() {
+            assert!(store.exists(&x.id).unwrap());
+        }
+        // nothing was written to the repo's own object store
+        assert_eq!(FsStore.iter().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn gc_keeps_age_of_unreachable_packed_objects() {
+        let _repo = TempRepo::new();
+        let store = FsStore;
+        write("a", "committed");
+        commit_all(&store, "first");
+        write("x", "unstaged later");
+        add(&store, &["x".to_string()], Some(1));
+        let lost = staged_id(&store, "x");
+        // staged objects are packed
+        gc_objects(&store, None, false).unwrap();
+        assert!(!data::object_path(&lost).exists());
+        rm(&store, &["x".to_string()], true);
+
+        let old = SystemTime::now() - 10 * Duration::from_secs(24 * 60 * 60);
+        for pack in fs::read_dir(crate::pack::PACK_DIR).unwrap() {
+            let file = fs::File::options().write(true).open(pack.unwrap().path());
+            file.unwrap().set_modified(old).unwrap();
+        }
+        for _ in 0..2 {
+            gc_objects(&store, Some(2 * WEEK), false).unwrap();
+            pack(&store);
+            assert!(data::object_path(&lost).exists());
+            assert_eq!(store.modified(&lost).unwrap(), old);
+        }
+        gc_objects(&store, Some(WEEK), false).unwrap();
+        assert!(!store.exists(&lost).unwrap());
+        // far beyond the epoch prunes nothing
+        gc_objects(&store, Some(100_000 * WEEK), false).unwrap();
+        assert!(store.exists(&staged_id(&store, "a")).unwrap());
+    }
+}